@@ -3,22 +3,25 @@
 import os
 import sys
 
-args, flags = [], set()
+args, flags, server_args = [], set(), []
 for arg in sys.argv[1:]:
-    if arg.startswith('-'):
+    if arg.startswith('--assets='):
+        server_args.append(arg)
+    elif arg.startswith('-'):
         flags.add(arg.lstrip('-')[0])
     else:
         args.append(arg)
 
 
 if len(args) != 1:
-    print('usage: dungeon.py [--debug | -d] <file_path>')
+    print('usage: dungeon.py [--debug | -d] [--flag-unsafe-urls | -f] [--assets=<folder>] <file_path>')
     exit(1)
 
 # file and directory to watch
 file_path = os.path.abspath(sys.argv[-1])
 dir_path = os.path.dirname(file_path)
 debug_mode = 'd' in flags
+flag_unsafe_urls = 'f' in flags
 
 # cd to the directory of this script
 pwd = os.path.dirname(os.path.abspath(__file__))
@@ -49,7 +52,8 @@ def build_shared():
 
 def build_and_run_rust(optimize = False):
     os.chdir('rust')
-    os.system(f'cargo run {"--release" if optimize else ""} -- "{file_path}" "{pwd}/build"')
+    options = (' --flag-unsafe-urls' if flag_unsafe_urls else '') + ''.join(f' "{arg}"' for arg in server_args)
+    os.system(f'cargo run {"--release" if optimize else ""} -- "{file_path}" "{pwd}/build"' + options)
     os.chdir(pwd)
 
 def clean():
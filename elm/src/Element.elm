@@ -7,15 +7,20 @@ import Tailwind.Utilities as Tw
 
 import Html.Styled exposing (Html, div, span, p, text, h1, h2, h3, h4, h5, h6
                                  , b, i, u, s, a, img, code, li, ol, ul
-                                 , blockquote, br, hr)
+                                 , dl, dt, dd, blockquote, br, hr, figure, figcaption)
 
 import Html.Styled as Styled
 import Html.Styled.Events as Events
 import Html.Styled.Attributes as Attributes exposing (css)
+import Svg.Styled as Svg
+import Svg.Styled.Attributes as SvgAttributes
+import Svg.Styled.Events as SvgEvents
 -- note to self: real css z-index should be data.zIndex + 10
 
 import Css
 
+import Json.Decode as Decode
+
 import Html.Attributes
 
 ----------------------------------- constants ----------------------------------
@@ -32,8 +37,11 @@ type alias ElementId = String
 
 type alias MouseOffset = { offsetX : Float, offsetY : Float }
 
-type ElementState = ESRect RectState -- | ESLine LineState
+type ElementState = ESRect RectState | ESLine LineState
 type RectState = RViewState | REditState | RDragState (DragType, MouseOffset)
+-- ink is only ever moved as a whole, so it's edited like a line that's only got LWhole
+type LineState = LViewState | LEditState | LDragState (LineHandle, MouseOffset)
+type LineHandle = LStart | LEnd | LWhole
 type DragType = DMove | DLeft | DRight | DTop | DBot | DTopLeft | DTopRight | DBotLeft | DBotRight
 
 dTypeLeftFree : DragType -> Bool
@@ -68,24 +76,44 @@ dTypeBotFree dType = case dType of
     DBotRight -> True
     _ -> False
 
-type Msg = Select | DragStart DragType
+-- moving an element up or down the page, which the server does (see Main)
+type ZOrder = ZForward | ZBackward | ZFront | ZBack
+
+zOrderName : ZOrder -> String
+zOrderName order = case order of
+    ZForward -> "forward"
+    ZBackward -> "backward"
+    ZFront -> "front"
+    ZBack -> "back"
+
+type Msg = Select | DragStart DragType | LineDragStart LineHandle | Reorder ZOrder
 
 ------------------------------------- init -------------------------------------
 
 initState : Element -> ElementState
 initState element = case element of
     TextBox _ -> ESRect RViewState
-    Line _ -> ESRect RViewState
+    Line _ -> ESLine LViewState
     Rect _ -> ESRect RViewState
+    ImageBox _ -> ESRect RViewState
+    Ink _ -> ESLine LViewState
+    Frame _ -> ESRect RViewState
 
 --------------------------------- update logic ---------------------------------
 
 -- mainly phrasing these as a series of transformers on an element, with the
 -- iteration logic handled in the module with the document itself.
 
+isSelected : ElementState -> Bool
+isSelected state = case state of
+    ESRect RViewState -> False
+    ESLine LViewState -> False
+    _ -> True
+
 deselect : (Element, ElementState) -> (Element, ElementState)
 deselect (data, state) = case state of
     ESRect _ -> (data, ESRect RViewState)
+    ESLine _ -> (data, ESLine LViewState)
 
 -- if a box is selected and in drag mode, update its position
 mousemove : MousePos -> AnchorPos -> (Element, ElementState) -> (Element, ElementState)
@@ -102,11 +130,15 @@ mousemove {x, y} anchorPos (data, state) = case state of
             (x0, y0, w0) = case data of
                 Rect d -> (d.x, d.y, d.width)
                 TextBox d -> (d.x, d.y, d.width)
+                ImageBox d -> (d.x, d.y, d.width)
+                Frame d -> (d.x, d.y, d.width)
                 _ -> (0, 0, 0)
 
             h0 = case data of
                 Rect d -> d.height
                 TextBox _ -> 100 -- just needs to be some non-zero value
+                ImageBox d -> d.height
+                Frame d -> d.height
                 _ -> 0
 
             x1 = if l then targetX else x0
@@ -124,16 +156,45 @@ mousemove {x, y} anchorPos (data, state) = case state of
         in case data of
             Rect d -> (Rect { d | x = x3, y = y3, width = w, height = h }, state)
             TextBox d -> (TextBox { d | x = x3, y = y3, width = w }, state)
+            ImageBox d -> (ImageBox { d | x = x3, y = y3, width = w, height = h }, state)
+            Frame d -> (Frame { d | x = x3, y = y3, width = w, height = h }, state)
             _ -> (data, state)
 
+    ESLine (LDragState (handle, { offsetX, offsetY })) -> case data of
+        Line d ->
+            let targetX = x - anchorPos.x - offsetX
+                targetY = y - anchorPos.y - offsetY
+            in case handle of
+                LStart -> (Line { d | x1 = targetX, y1 = targetY }, state)
+                LEnd -> (Line { d | x2 = targetX, y2 = targetY }, state)
+                LWhole -> (Line { d | x1 = targetX, y1 = targetY, x2 = targetX + d.x2 - d.x1, y2 = targetY + d.y2 - d.y1 }, state)
+        Ink d -> (Ink { d | x = x - anchorPos.x - offsetX, y = y - anchorPos.y - offsetY }, state)
+        _ -> (data, state)
+
     _ -> (data, state)
 
 
 -- a more general update function. The last return value is whether or not we
 -- need to send an update back to the server
 
+isLocked : Element -> Bool
+isLocked element = case element of
+    Rect d -> d.locked
+    TextBox d -> d.locked
+    ImageBox d -> d.locked
+    Line d -> d.locked
+    Ink d -> d.locked
+    Frame d -> d.locked
+
+-- locked elements can still be selected (and edited), just not dragged around
+isDragStart : Msg -> Bool
+isDragStart msg = case msg of
+    DragStart _ -> True
+    LineDragStart _ -> True
+    _ -> False
+
 update : MousePos -> AnchorPos -> Msg -> (Element, ElementState) -> ((Element, ElementState), Bool)
-update mousePos anchorPos msg (data, state) = case (data, state) of
+update mousePos anchorPos msg (data, state) = if isLocked data && isDragStart msg then ((data, state), False) else case (data, state) of
 
     (Rect d, ESRect s) -> case (msg, s) of
         (Select, RViewState) -> ((data, ESRect REditState), False)
@@ -142,6 +203,20 @@ update mousePos anchorPos msg (data, state) = case (data, state) of
 
         _ -> ((data, state), False)
 
+    (ImageBox d, ESRect s) -> case (msg, s) of
+        (Select, RViewState) -> ((data, ESRect REditState), False)
+        (DragStart dType, REditState) ->
+            ((data, ESRect (RDragState (dType, mouseOffset dType mousePos anchorPos d.x d.y d.width d.height) )), False)
+
+        _ -> ((data, state), False)
+
+    (Frame d, ESRect s) -> case (msg, s) of
+        (Select, RViewState) -> ((data, ESRect REditState), False)
+        (DragStart dType, REditState) ->
+            ((data, ESRect (RDragState (dType, mouseOffset dType mousePos anchorPos d.x d.y d.width d.height) )), False)
+
+        _ -> ((data, state), False)
+
     (TextBox d, ESRect s) -> case (msg, s) of
         (Select, RViewState) -> ((data, ESRect REditState), False)
         (DragStart dType, REditState) ->
@@ -149,6 +224,24 @@ update mousePos anchorPos msg (data, state) = case (data, state) of
 
         _ -> ((data, state), False)
 
+    -- the offset is from the end being dragged, or the start when dragging the whole line
+    (Line d, ESLine s) -> case (msg, s) of
+        (Select, LViewState) -> ((data, ESLine LEditState), False)
+        (LineDragStart handle, LEditState) ->
+            let (px, py) = if handle == LEnd then (d.x2, d.y2) else (d.x1, d.y1)
+                offset = { offsetX = mousePos.x - anchorPos.x - px, offsetY = mousePos.y - anchorPos.y - py }
+            in ((data, ESLine (LDragState (handle, offset))), False)
+
+        _ -> ((data, state), False)
+
+    (Ink d, ESLine s) -> case (msg, s) of
+        (Select, LViewState) -> ((data, ESLine LEditState), False)
+        (LineDragStart LWhole, LEditState) ->
+            let offset = { offsetX = mousePos.x - anchorPos.x - d.x, offsetY = mousePos.y - anchorPos.y - d.y }
+            in ((data, ESLine (LDragState (LWhole, offset))), False)
+
+        _ -> ((data, state), False)
+
     _ -> ((data, state), False)
 
 -- Just newState = update the element's state and send result back to server
@@ -159,6 +252,9 @@ mouseUp state = case state of
     ESRect s -> case s of
         RDragState _ -> ESRect REditState |> Just
         _ -> Nothing
+    ESLine s -> case s of
+        LDragState _ -> ESLine LEditState |> Just
+        _ -> Nothing
 
 
 mouseOffset : DragType -> MousePos -> AnchorPos -> Float -> Float -> Float -> Float -> MouseOffset
@@ -180,16 +276,24 @@ viewElement converter (k, (e, s)) =
     case (s, e) of
         (ESRect state, TextBox data) -> viewTextBox converter (k, (data, state))
         (ESRect state, Rect data) -> viewRect converter (k, (data, state))
+        (ESRect state, ImageBox data) -> viewImage converter (k, (data, state))
+        (ESLine state, Line data) -> viewLine converter (k, (data, state))
+        (ESLine state, Ink data) -> viewInk converter (k, (data, state))
+        (ESRect state, Frame data) -> viewFrame converter (k, (data, state))
         _ -> text "other object types not yet implemented"
 
 
 ----------------------------- stretchy bounding box ----------------------------
 
-viewBounding : (ElementId -> Msg -> msg) -> Html msg -> (ElementId, ({ x : Float, y : Float, width : Float, height : Maybe Float, z : Int }, RectState)) -> Html msg
+viewBounding : (ElementId -> Msg -> msg) -> Html msg -> (ElementId, ({ x : Float, y : Float, width : Float, height : Maybe Float, z : Int, rotation : Float }, RectState)) -> Html msg
 viewBounding converter content (k, (data, state)) =
 
         let style = css <| [ Tw.absolute, Css.width (Css.px data.width)
                            , Css.left (Css.px data.x), Css.top (Css.px data.y) ]
+                           ++ ( if data.rotation == 0 then [] else
+                               -- around the top left corner, same as the server does it
+                               [ Css.transforms [ Css.rotate (Css.deg data.rotation) ], Css.property "transform-origin" "top left" ]
+                           )
                            ++ ( case data.height of
                                Just h -> [ Css.height (Css.px h) ]
                                Nothing -> []
@@ -216,14 +320,18 @@ viewBounding converter content (k, (data, state)) =
                 RViewState -> [ Events.onClick (converter k Select) ]
                 _ -> [ Events.onMouseDown (converter k (DragStart DMove)) ]
 
-            -- add 8 floating drag handles to the sides and corners
+            -- add 8 floating drag handles to the sides and corners, and the z-order buttons
             children = case state of
                 RViewState -> []
-                _ -> [ (-1, 0, DLeft), (1, 0, DRight), (0, -1, DTop), (0, 1, DBot),
+                _ -> ([ (-1, 0, DLeft), (1, 0, DRight), (0, -1, DTop), (0, 1, DBot),
                        (-1, -1, DTopLeft), (1, 1, DBotRight), (1, -1, DTopRight), (-1, 1, DBotLeft) ]
-                     |> List.map (\(x, y, dir) -> viewDragHandle converter k (x, y) dir)
+                     |> List.map (\(x, y, dir) -> viewDragHandle converter k (x, y) dir))
+                     ++ [ viewZOrderButtons converter k ]
+
+            -- so the page's javascript can tell which element something was pasted or dropped on
+            id = Attributes.attribute "data-element" k
 
-        in div (style::events) (content::children)
+        in div (style::id::events) (content::children)
 
 
 viewDragHandle : (ElementId -> Msg -> msg) -> ElementId -> (Float, Float) -> DragType -> Html msg
@@ -255,46 +363,264 @@ viewDragHandle converter k (x, y) dir =
 
     in div (style::events) []
 
+-- a row of buttons above the top right corner, for moving the element up or down the page
+viewZOrderButtons : (ElementId -> Msg -> msg) -> ElementId -> Html msg
+viewZOrderButtons converter k =
+    let button (label, title, order) =
+            Styled.button [ css [ Tw.bg_gray_100, Tw.text_black, Tw.text_xs, Tw.px_1, Tw.border_none, Tw.cursor_pointer ]
+                          , Attributes.title title
+                          -- on mousedown, and not letting it through to the box, which would start a drag
+                          , Events.stopPropagationOn "mousedown" (Decode.succeed (converter k (Reorder order), True))
+                          ] [ text label ]
+
+    in div [ css [ Tw.absolute, Tw.flex, Tw.gap_px, Css.bottom (Css.pct 100), Css.right Css.zero, Css.marginBottom (Css.px resizeRegionSize) ] ]
+           (List.map button [ ("⤓", "send to back", ZBack), ("↓", "send backward", ZBackward)
+                            , ("↑", "bring forward", ZForward), ("⤒", "bring to front", ZFront) ])
+
 ------------------------------------- rect -------------------------------------
 
-viewRect : (ElementId -> Msg -> msg) -> (ElementId, ({ x : Float, y : Float, width : Float, height : Float, z : Int, color : String }, RectState)) -> Html msg
+viewRect : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x : Float, y : Float, width : Float, height : Float, z : Int, rotation : Float, color : Color, shape : Shape, stroke : Maybe Color, strokeWidth : Float, style : StrokeStyle, opacity : Float }, RectState)) -> Html msg
 viewRect converter (k, (data, state)) =
-    let content = div [css <| [Css.backgroundColor (Css.hex data.color), Tw.w_full, Tw.h_full]] []
+    let border = case data.stroke of
+            Just stroke -> [ Css.borderWidth (Css.px data.strokeWidth), Css.borderColor (Css.hex stroke.css)
+                           , Css.borderStyle (case data.style of
+                                Solid -> Css.solid
+                                Dashed -> Css.dashed
+                                Dotted -> Css.dotted) ]
+            Nothing -> []
+
+        box rounding = div [css <| [ Css.backgroundColor (Css.hex data.color.css), Tw.w_full, Tw.h_full, Css.opacity (Css.num data.opacity) ] ++ rounding ++ border] []
+
+        -- polygons are drawn in a 1 by 1 box stretched over the whole element, which keeps their
+        -- points as fractions of it, but the stroke is still measured in pixels
+        outline closed points =
+            let stroke = Maybe.withDefault (if closed then "none" else data.color.css) (Maybe.map .css data.stroke)
+                shape = if closed then Svg.polygon else Svg.polyline
+            in Svg.svg [ SvgAttributes.viewBox "0 0 1 1", SvgAttributes.preserveAspectRatio "none"
+                       , SvgAttributes.css [ Tw.w_full, Tw.h_full, Css.overflow Css.visible, Css.opacity (Css.num data.opacity) ] ]
+                       [ shape ([ SvgAttributes.points (String.join " " (List.map (\p -> String.fromFloat p.x ++ "," ++ String.fromFloat p.y) points))
+                                , SvgAttributes.fill (if closed then data.color.css else "none")
+                                , SvgAttributes.stroke stroke, SvgAttributes.strokeWidth (String.fromFloat data.strokeWidth)
+                                , SvgAttributes.strokeLinejoin "round"
+                                , Attributes.attribute "vector-effect" "non-scaling-stroke"
+                                ] ++ strokeDashes data.style data.strokeWidth) [] ]
+
+        content = case data.shape of
+            Rectangle -> box []
+            RoundedRect { radius } -> box [ Css.borderRadius (Css.px radius) ]
+            Ellipse -> box [ Css.borderRadius (Css.pct 50) ]
+            Polygon { points } -> outline True points
+            Polyline { points } -> outline False points
+
     in viewBounding converter content (k, ({
             x      = data.x,
             y      = data.y,
             z      = data.z,
+            rotation = data.rotation,
+            width  = data.width,
+            height = Just data.height
+    } , state))
+
+------------------------------------- frame ------------------------------------
+
+-- just an outline with the title over its top left corner. What's in it is moved along with it by
+-- the server, which then has us reload.
+viewFrame : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x : Float, y : Float, width : Float, height : Float, z : Int, title : String }, RectState)) -> Html msg
+viewFrame converter (k, (data, state)) =
+    let title = div [ css [ Tw.absolute, Css.bottom (Css.pct 100), Css.left Css.zero, Tw.text_sm, Tw.opacity_70, Tw.whitespace_nowrap ] ]
+                    [ text data.title ]
+
+        content = div [ css [ Tw.relative, Tw.w_full, Tw.h_full, Tw.border, Tw.border_dashed, Css.borderColor (Css.rgba 255 255 255 0.4) ] ]
+                      (if String.isEmpty data.title then [] else [ title ])
+
+    in viewBounding converter content (k, ({
+            x      = data.x,
+            y      = data.y,
+            z      = data.z,
+            rotation = 0,
+            width  = data.width,
+            height = Just data.height
+    } , state))
+
+------------------------------------- line -------------------------------------
+
+-- the dash pattern for a line (or outline) width pixels wide
+strokeDashes : StrokeStyle -> Float -> List (Svg.Attribute msg)
+strokeDashes style width =
+    let px = String.fromFloat in
+    case style of
+        Solid -> []
+        Dashed -> [ SvgAttributes.strokeDasharray (px (4 * width) ++ " " ++ px (3 * width)) ]
+        Dotted -> [ SvgAttributes.strokeDasharray ("0 " ++ px (2.5 * width)), SvgAttributes.strokeLinecap "round" ]
+
+viewLine : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x1 : Float, y1 : Float, x2 : Float, y2 : Float, z : Int, color : Color, width : Float, style : StrokeStyle, startArrow : Bool, endArrow : Bool, from : Maybe String, to : Maybe String }, LineState)) -> Html msg
+viewLine converter (k, (data, state)) =
+    let -- the svg only covers the line itself, with some room around it for arrowheads and handles
+        margin = 10 + 4 * data.width
+        left = min data.x1 data.x2 - margin
+        top = min data.y1 data.y2 - margin
+
+        px = String.fromFloat
+        ends = [ SvgAttributes.x1 (px (data.x1 - left)), SvgAttributes.y1 (px (data.y1 - top))
+               , SvgAttributes.x2 (px (data.x2 - left)), SvgAttributes.y2 (px (data.y2 - top)) ]
+
+        markerId = "arrow-" ++ k
+        arrowhead = Svg.marker [ SvgAttributes.id markerId, SvgAttributes.viewBox "0 0 10 10"
+                               , SvgAttributes.refX "8", SvgAttributes.refY "5"
+                               , SvgAttributes.markerWidth "5", SvgAttributes.markerHeight "5"
+                               , SvgAttributes.orient "auto-start-reverse"
+                               ] [ Svg.path [ SvgAttributes.d "M 0 0 L 10 5 L 0 10 z", SvgAttributes.fill data.color.css ] [] ]
+
+        arrows = (if data.startArrow then [ SvgAttributes.markerStart ("url(#" ++ markerId ++ ")") ] else [])
+              ++ (if data.endArrow then [ SvgAttributes.markerEnd ("url(#" ++ markerId ++ ")") ] else [])
+
+        dashes = strokeDashes data.style data.width
+
+        stroke = Svg.line (ends ++ dashes ++ arrows ++ [ SvgAttributes.stroke data.color.css, SvgAttributes.strokeWidth (px data.width) ]) []
+
+        -- a wider invisible line over the top, so thin lines are still easy to grab
+        events = case state of
+            LViewState -> [ SvgEvents.onClick (converter k Select) ]
+            _ -> [ SvgEvents.onMouseDown (converter k (LineDragStart LWhole)) ]
+
+        hitbox = Svg.line (ends ++ events ++ [ SvgAttributes.stroke "transparent", SvgAttributes.strokeWidth (px (data.width + 10))
+                                             , SvgAttributes.css [ Css.property "pointer-events" "stroke", Tw.cursor_move ] ]) []
+
+        handle (x, y) end = Svg.circle [ SvgAttributes.cx (px (x - left)), SvgAttributes.cy (px (y - top)), SvgAttributes.r "5"
+                                       , SvgAttributes.fill "white", SvgAttributes.css [ Css.property "pointer-events" "all", Tw.cursor_move ]
+                                       , SvgEvents.onMouseDown (converter k (LineDragStart end))
+                                       ] []
+
+        (handles, zIndex) = case state of
+            LViewState -> ([], data.z + 10) -- only the line itself catches the mouse, so it can sit over text
+            _ -> ([ handle (data.x1, data.y1) LStart, handle (data.x2, data.y2) LEnd ], data.z + 1000)
+
+    in Svg.svg [ SvgAttributes.width (px (abs (data.x2 - data.x1) + 2 * margin))
+               , SvgAttributes.height (px (abs (data.y2 - data.y1) + 2 * margin))
+               , SvgAttributes.css [ Tw.absolute, Css.left (Css.px left), Css.top (Css.px top), Css.zIndex (Css.int zIndex)
+                                   , Css.property "pointer-events" "none", Css.overflow Css.visible ]
+               ] ([ Svg.defs [] [ arrowhead ], stroke, hitbox ] ++ handles)
+
+------------------------------------- ink --------------------------------------
+
+viewInk : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x : Float, y : Float, z : Int, color : Color, width : Float, strokes : List (List InkPoint) }, LineState)) -> Html msg
+viewInk converter (k, (data, state)) =
+    let points = List.concat data.strokes
+        margin = data.width
+        left = (List.minimum (List.map .x points) |> Maybe.withDefault 0) - margin
+        top = (List.minimum (List.map .y points) |> Maybe.withDefault 0) - margin
+        right = (List.maximum (List.map .x points) |> Maybe.withDefault 0) + margin
+        bottom = (List.maximum (List.map .y points) |> Maybe.withDefault 0) + margin
+
+        px = String.fromFloat
+        thickness a b = px (data.width * (a.pressure + b.pressure) / 2)
+
+        -- each segment is drawn as its own line, so the width can follow the pressure along the stroke
+        segment a b = Svg.line [ SvgAttributes.x1 (px (a.x - left)), SvgAttributes.y1 (px (a.y - top))
+                               , SvgAttributes.x2 (px (b.x - left)), SvgAttributes.y2 (px (b.y - top))
+                               , SvgAttributes.strokeWidth (thickness a b) ] []
+
+        dot a = Svg.circle [ SvgAttributes.cx (px (a.x - left)), SvgAttributes.cy (px (a.y - top))
+                           , SvgAttributes.r (px (data.width * a.pressure / 2)), SvgAttributes.fill data.color.css ] []
+
+        viewStroke stroke = case stroke of
+            [ a ] -> [ dot a ]
+            _ -> List.map2 segment stroke (List.drop 1 stroke)
+
+        events = case state of
+            LViewState -> [ SvgEvents.onClick (converter k Select) ]
+            _ -> [ SvgEvents.onMouseDown (converter k (LineDragStart LWhole)) ]
+
+        (outline, zIndex) = case state of
+            LViewState -> ([], data.z + 10)
+            LEditState -> ([ Css.outline3 (Css.px 1) Css.dashed (Css.hex "ffffff"), Tw.cursor_move ], data.z + 1000)
+            LDragState _ -> ([ Css.outline3 (Css.px 1) Css.dashed (Css.hex "ffffff"), Css.cursor Css.grabbing ], data.z + 1000)
+
+    in Svg.svg ([ SvgAttributes.width (px (right - left)), SvgAttributes.height (px (bottom - top))
+                , SvgAttributes.stroke data.color.css, SvgAttributes.strokeLinecap "round", SvgAttributes.fill "none"
+                , SvgAttributes.css ([ Tw.absolute, Css.left (Css.px (data.x + left)), Css.top (Css.px (data.y + top))
+                                     , Css.zIndex (Css.int zIndex) ] ++ outline)
+                ] ++ events) (List.concatMap viewStroke data.strokes)
+
+------------------------------------- image ------------------------------------
+
+viewImage : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x : Float, y : Float, width : Float, height : Float, z : Int, rotation : Float, src : String, url : String, crop : Maybe Crop }, RectState)) -> Html msg
+viewImage converter (k, (data, state)) =
+    let -- stretch the image so the cropped part fills the box, and hide the rest
+        placement = case data.crop of
+            Nothing -> [ Tw.w_full, Tw.h_full ]
+            Just crop -> [ Css.left (Css.pct (-100 * crop.x / crop.width)), Css.top (Css.pct (-100 * crop.y / crop.height))
+                         , Css.width (Css.pct (100 / crop.width)), Css.height (Css.pct (100 / crop.height)) ]
+
+        image = img [ Attributes.src data.url
+                    , Attributes.attribute "draggable" "false"
+                    , css ([ Tw.absolute, Css.margin Css.zero, Css.maxWidth Css.none, Tw.pointer_events_none ] ++ placement)
+                    ] []
+
+        content = div [ css [ Tw.relative, Tw.w_full, Tw.h_full, Tw.overflow_hidden ] ] [ image ]
+
+    in viewBounding converter content (k, ({
+            x      = data.x,
+            y      = data.y,
+            z      = data.z,
+            rotation = data.rotation,
             width  = data.width,
             height = Just data.height
     } , state))
 
 --------------------------------- markdown view --------------------------------
 
-viewTextBox : (ElementId -> Msg -> msg) -> (ElementId, ({ x : Float, y : Float, width : Float, data : List (TextBlock) }, RectState)) -> Html msg
+viewTextBox : (ElementId -> Msg -> msg) -> (ElementId, ({ a | x : Float, y : Float, width : Float, z : Int, rotation : Float, style : TextStyle, markdown : Markdown, data : List (TextBlock) }, RectState)) -> Html msg
 viewTextBox converter (k, (data, state)) =
-    let padding = 18 in
-    let content = div [css <| [ Css.width (Css.px data.width)
-                              , Tw.h_full
-                              , Css.paddingLeft (Css.px padding)
-                              , Css.paddingRight (Css.px padding)
-                          ]] <| List.map viewTextBlock data.data
+    let padding = Maybe.withDefault 18 data.style.padding
+
+        classes = case data.style.class of
+            Just names -> [ Attributes.class names ]
+            Nothing -> []
+    in
+    let content = div ([css <| [ Css.width (Css.px data.width)
+                               , Tw.h_full
+                               , Css.paddingLeft (Css.px padding)
+                               , Css.paddingRight (Css.px padding)
+                               ] ++ textStyle data.style
+                       ] ++ classes) <| List.map viewTextBlock data.data
 
     in viewBounding converter content (k, ({
             x      = data.x,
             y      = data.y,
-            z      = 0,
+            z      = data.z,
+            rotation = data.rotation,
             width  = data.width + 2 * padding,
             height = Nothing
     } , state))
 
 
+-- the server only lets through values that are safe to put straight into css
+textStyle : TextStyle -> List Css.Style
+textStyle style =
+    let optional f = Maybe.map f >> Maybe.withDefault []
+    in optional (\color -> [ Css.backgroundColor (Css.hex color.css) ]) style.background
+       ++ optional (\color -> [ Css.border3 (Css.px (Maybe.withDefault 1 style.borderWidth)) Css.solid (Css.hex color.css) ]) style.border
+       -- set padding covers the top and bottom too, which are left alone otherwise
+       ++ optional (\padding -> [ Css.paddingTop (Css.px padding), Css.paddingBottom (Css.px padding) ]) style.padding
+       ++ optional (\font -> [ Css.fontFamilies (List.map String.trim (String.split "," font)) ]) style.font
+       ++ optional (\size -> [ Css.fontSize (Css.px size) ]) style.fontSize
+       ++ optional (\color -> [ Css.color (Css.hex color.css) ]) style.color
+       ++ optional (\align -> [ case align of
+                                    Left -> Css.textAlign Css.left
+                                    Center -> Css.textAlign Css.center
+                                    Right -> Css.textAlign Css.right
+                                    Justify -> Css.textAlign Css.justify ]) style.align
+
+
 viewTextBlock : TextBlock -> Html msg
 viewTextBlock block =
 
-    let viewListItem item = case item of
-            OrderedList _ -> viewTextBlock item
-            UnorderedList _ -> viewTextBlock item
-            _ -> li [] [viewTextBlock item]
+    let viewListItem item = li [] (List.map viewTextBlock item)
+
+        viewDefinitionItem { terms, definitions } =
+            List.map (dt [] << List.map viewTextChunk) terms
+                ++ List.map (dd [] << List.map viewTextBlock) definitions
 
     in case block of
 
@@ -316,11 +642,44 @@ viewTextBlock block =
 
         UnorderedList { items } -> ul [] (List.map viewListItem items)
 
-        OrderedList { items } -> ol [] (List.map viewListItem items)
+        OrderedList { start, style, delimiter, items } ->
+            let listStyle = case style of
+                    Decimal -> "decimal"
+                    LowerRoman -> "lower-roman"
+                    UpperRoman -> "upper-roman"
+                    LowerAlpha -> "lower-alpha"
+                    UpperAlpha -> "upper-alpha"
+
+            in ol [ Attributes.start start
+                  , Attributes.classList [ ( "paren", delimiter == Paren ) ]
+                  , css [ Css.property "list-style-type" listStyle, Css.property "--list-style" listStyle ]
+                  ] (List.map viewListItem items)
 
         BlockQuote { inner } -> blockquote [] (List.map viewTextBlock inner)
 
-        Image { url, alt } -> img [ Attributes.src url, Attributes.alt alt ] []
+        Callout { kind, title, folded, inner } ->
+            let heading = if List.isEmpty title then [ text (capitalize kind) ] else List.map viewTextChunk title
+                attributes = [ Attributes.class "callout", Attributes.class ("callout-" ++ kind) ]
+            in case folded of
+                Nothing -> div attributes <| div [ Attributes.class "callout-title" ] heading :: List.map viewTextBlock inner
+                Just f -> Styled.node "details" (attributes ++ if f then [] else [ Attributes.attribute "open" "" ])
+                              <| Styled.node "summary" [ Attributes.class "callout-title" ] heading :: List.map viewTextBlock inner
+
+        DefinitionList { items } -> dl [] (List.concatMap viewDefinitionItem items)
+
+        Image { url, alt, title, caption, width, height, thumbnail } ->
+            let optional attribute = Maybe.map attribute >> Maybe.withDefault (Attributes.classList [])
+                image = img [ Attributes.src (Maybe.withDefault url thumbnail)
+                            , Attributes.alt alt
+                            , optional Attributes.title title
+                            , optional Attributes.width width
+                            , optional Attributes.height height
+                            ] []
+                        -- a thumbnail links to the full size original
+                        |> \shown -> if thumbnail == Nothing then shown
+                                     else a [ Attributes.href url, Attributes.target "_blank" ] [ shown ]
+            in if List.isEmpty caption then image
+               else figure [] [ image, figcaption [] (List.map viewTextChunk caption) ]
 
         VerticalSpace -> div [ css [ Css.height (Css.px 20) ] ] []
 
@@ -341,6 +700,10 @@ viewTextChunk chunk = case chunk of
     NewLine                  -> br [] []
 
 
+capitalize : String -> String
+capitalize str = String.toUpper (String.left 1 str) ++ String.dropLeft 1 str
+
+
 -- render raw html from a string
 innerHtml : String -> Styled.Html msg
 innerHtml content = Styled.node "inner-html" [ Attributes.attribute "content" content ] []
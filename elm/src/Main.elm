@@ -32,7 +32,7 @@ port sseError : (String -> msg) -> Sub msg
 
 ---------------------------------- model types ---------------------------------
 
-type Model = Loading 
+type Model = Loading Bool -- whether to show draft (hidden) elements
            | Loaded (PersistentState, VolatileState)
            | Desync String PersistentState -- holds the last known good state
            | Failed Http.Error
@@ -43,6 +43,7 @@ type alias VolatileState = { anchorPos : AnchorPos
                            , mousePos : MousePos
                            , elements : Dict ElementId ElementState
                            , canSelectText : Bool
+                           , showDrafts : Bool
                            }
 
 --------------------------------- message types --------------------------------
@@ -52,19 +53,23 @@ type Msg = LoadDocument (Result Http.Error PersistentState)
          | ElementMsg (ElementId, Element.Msg)
          | MouseUp -- stop dragging any elements currently being dragged
          | Deselect -- deselect all elements. <esc> key + when clicking on background (todo)
+         | ReorderSelected Element.ZOrder -- move whatever's selected up or down. [ ] { and } keys
          | MouseMove MousePos -- fired when the mouse moves
          | Posted (Result Http.Error ())
          | FileChange
          | SSEError String
          | Reload -- reload the page itself
 
-init : () -> (Model, Cmd Msg)
-init _ = (Loading, fetchData)
+-- the flag is set when the page is opened with ?drafts, see index.html
+init : Bool -> (Model, Cmd Msg)
+init showDrafts = (Loading showDrafts, fetchData showDrafts)
 
 ------------------------------------- load -------------------------------------
 
-fetchData : Cmd Msg
-fetchData = Http.get { url = "/fetch", expect = Http.expectJson LoadDocument documentDecoder }
+fetchData : Bool -> Cmd Msg
+fetchData showDrafts =
+    let url = if showDrafts then "/fetch?drafts=true" else "/fetch"
+    in Http.get { url = url, expect = Http.expectJson LoadDocument documentDecoder }
 
 loadAnchorPos : Cmd Msg
 loadAnchorPos = getElement "anchor-div" 
@@ -77,14 +82,22 @@ loadAnchorPos = getElement "anchor-div"
                 -- let _ = Debug.log "Failed to load anchor position" er in
                 SetAnchorPos { x = 0, y = 0 })
 
-initVolatileState : PersistentState -> VolatileState
-initVolatileState data = 
+initVolatileState : Bool -> PersistentState -> VolatileState
+initVolatileState showDrafts data = 
     { anchorPos = { x = 0, y = 0 }
     , mousePos = { x = 0, y = 0 }
     , elements = Dict.map (\_ -> Element.initState) data.elements
     , canSelectText = True
+    , showDrafts = showDrafts
     }
 
+-- the show drafts flag, carried through reloads
+draftsShown : Model -> Bool
+draftsShown model = case model of
+    Loading showDrafts -> showDrafts
+    Loaded (_, vol) -> vol.showDrafts
+    _ -> False
+
 
 ------------------------------------- logic ------------------------------------
 
@@ -95,13 +108,13 @@ update msg model =
         -------------------- load document, update volatiles -------------------
 
         (_, LoadDocument (Ok data)) -> 
-            ( Loaded (data, initVolatileState data)
+            ( Loaded (data, initVolatileState (draftsShown model) data)
             , Cmd.batch [loadAnchorPos] )
 
         (_, LoadDocument (Err err)) -> (Failed err, Cmd.none)
 
         -- reload data on hearing that the file has changed
-        (_, FileChange) -> (Loading, fetchData)
+        (_, FileChange) -> (Loading (draftsShown model), fetchData (draftsShown model))
 
         -- throw up a desync prompt if we get an error back on post. We don't
         -- just want to silently re-fetch data here, as this probably means the
@@ -164,6 +177,14 @@ update msg model =
             in (Loaded (doc1, volatiles1), Cmd.none)
 
 
+        -- z-order is changed by the server, which can renumber other elements to make room, and then
+        -- tells us to reload
+        (Loaded _, ElementMsg (target, Element.Reorder order)) -> (model, reorderElement target order)
+
+        (Loaded (doc, volatiles), ReorderSelected order) ->
+            let selected = Dict.keys <| Dict.filter (\_ -> Element.isSelected) volatiles.elements
+            in (model, Cmd.batch (List.map (\k -> reorderElement k order) selected))
+
         (Loaded (doc, volatiles), ElementMsg (target, e_msg)) ->
             -- let _ = Debug.log "ElementMsg" (target, e_msg) in
 
@@ -222,7 +243,7 @@ view model =
         Failed err -> text "Failed to load data."
 
         Desync message doc ->
-            let baseHtml = Loaded (doc, initVolatileState doc) |> view
+            let baseHtml = Loaded (doc, initVolatileState False doc) |> view
 
                 baseHtmlDark = div [ ]
                                [ div [ css [ Tw.absolute, Tw.inset_0, Tw.bg_black, Tw.opacity_70, Tw.z_40 ] ] []
@@ -241,7 +262,7 @@ view model =
                    , baseHtmlDark
                    ]
 
-        Loading -> 
+        Loading _ -> 
             div [ css [ Tw.absolute, Tw.inset_0, Tw.flex, Tw.items_center, Tw.justify_center ] ]
                 [ h2 [ css [ Tw.text_center, Tw.opacity_25 ] ] [ text "loading..." ] ]
 
@@ -250,9 +271,19 @@ view model =
                                                   (Dict.toList <| zip doc.elements vol.elements)
                   textSelection = if not vol.canSelectText then [Tw.select_none] else []
               in div [ css (textSelection ++ [ Tw.top_0, Tw.w_full, Tw.h_screen ]) ]
-                     [ div [ Attributes.id "anchor-div", css [ Tw.top_0, Tw.absolute, Css.left (Css.vw 50) ] ]
+                     ( div [ Attributes.id "anchor-div", css [ Tw.top_0, Tw.absolute, Css.left (Css.vw 50) ] ]
                          textBoxesHtml
-                     ]
+                     :: viewDiagnostics doc.diagnostics
+                     )
+
+
+-- anything the server had to say about the document, in the corner
+viewDiagnostics : List Diagnostic -> List (Html Msg)
+viewDiagnostics diagnostics =
+    if List.isEmpty diagnostics then []
+    else [ div [ css [ Tw.fixed, Tw.bottom_0, Tw.right_0, Tw.m_4, Tw.p_2, Tw.bg_gray_100, Tw.text_black, Tw.text_sm, Tw.z_50 ] ]
+               (List.map (\d -> div [] [ text d.message ]) diagnostics)
+         ]
 
 ------------------------------------ effects -----------------------------------
 
@@ -268,6 +299,13 @@ updateElement docCreated id data =
                  , url = url
                  }
 
+reorderElement : ElementId -> Element.ZOrder -> Cmd Msg
+reorderElement id order =
+    Http.post { body = Http.emptyBody
+              , expect = Http.expectWhatever Posted
+              , url = "/reorder/" ++ id ++ "/" ++ Element.zOrderName order
+              }
+
 
 subscriptions : Model -> Sub Msg
 subscriptions _ = 
@@ -286,14 +324,25 @@ subscriptions _ =
                 (\key -> if key == "Escape" then Decode.succeed Deselect else Decode.fail "wrong key")
             )
 
+        -- the same keys as most drawing programs use (without ctrl), for z-order
+        reorderSub = onKeyDown (
+                Decode.field "key" Decode.string |> Decode.andThen
+                (\key -> case key of
+                    "]" -> Decode.succeed (ReorderSelected Element.ZForward)
+                    "[" -> Decode.succeed (ReorderSelected Element.ZBackward)
+                    "}" -> Decode.succeed (ReorderSelected Element.ZFront)
+                    "{" -> Decode.succeed (ReorderSelected Element.ZBack)
+                    _ -> Decode.fail "wrong key")
+            )
+
         -- subscribe to a SSE stream to hear if the file changed
         fileSub = fileChange (\_ -> FileChange)
 
         sseErrorSub = sseError (\s -> SSEError s)
 
-    in Sub.batch [ mouseMoveSub, mouseUpSub, escapeSub, fileSub, sseErrorSub ]
+    in Sub.batch [ mouseMoveSub, mouseUpSub, escapeSub, reorderSub, fileSub, sseErrorSub ]
 
 
 
-main : Program () Model Msg
+main : Program Bool Model Msg
 main = Browser.element { init = init, update = update, view = view >> Html.Styled.toUnstyled, subscriptions = subscriptions }
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+
+use sha2::{Digest, Sha256};
+
+// Content-addressed cache for the expensive bits of parsing (katex and prism both run through an
+// embedded JS engine), and for image thumbnails. Every render is keyed by (renderer, options,
+// source), and lives in two layers: a small in-memory map so a reload with unchanged blocks costs
+// nothing, and a directory on disk (usually `.dungeon/cache` next to the document) so a fresh
+// server start doesn't have to re-render hundreds of equations.
+//
+// Until `init` is called, only the in-memory layer is used - this is what happens in tests.
+
+// in-memory layer: once it gets too big, throw everything out and start again.
+const MEMORY_CACHE_SIZE: usize = 1024;
+
+// on-disk layer: once it's over the limit, delete least recently used files until we're back
+// under 3/4 of it.
+const DISK_CACHE_LIMIT: u64 = 64 * 1024 * 1024;
+
+// Part of every key, along with the crate's version, so renders left on disk by an older build
+// (which might have rendered things differently) are never used. Bump it when a renderer's output
+// changes without a new version.
+const FORMAT_VERSION: u32 = 1;
+
+// Only the bookkeeping is done under the lock. Reading and writing the files themselves isn't, so
+// one slow render being saved doesn't hold up every other lookup.
+struct DiskCache {
+    dir: PathBuf,
+    size: u64, // approximate total size of the files in dir, in bytes
+    limit: u64, // DISK_CACHE_LIMIT, other than in tests
+    evicting: bool, // so only one thread at a time goes through deleting files
+}
+
+lazy_static! {
+    static ref MEMORY_CACHE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref DISK_CACHE: Mutex<Option<DiskCache>> = Mutex::new(None);
+}
+
+pub fn init(dir: PathBuf) {
+    init_disk(&DISK_CACHE, dir, DISK_CACHE_LIMIT);
+}
+
+fn init_disk(disk: &Mutex<Option<DiskCache>>, dir: PathBuf, limit: u64) {
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("unable to create render cache at {}: {}", dir.display(), e);
+        return;
+    }
+
+    let size = cache_files(&dir).iter().map(|(_, len, _)| len).sum();
+
+    *disk.lock().unwrap() = Some(DiskCache { dir: dir.clone(), size, limit, evicting: size > limit });
+    if size > limit { evict(disk, &dir); }
+}
+
+// Look up a render in the cache, calling `render` to produce (and then store) it on a miss.
+pub fn get_or_render(renderer: &str, options: &str, source: &str, render: impl FnOnce() -> String) -> String {
+    let key = cache_key(renderer, options, source);
+
+    if let Some(html) = MEMORY_CACHE.lock().unwrap().get(&key) {
+        return html.clone();
+    }
+
+    let html = read_disk(&DISK_CACHE, &key).and_then(|bytes| String::from_utf8(bytes).ok()).unwrap_or_else(|| {
+        let html = render();
+        write_disk(&DISK_CACHE, &key, html.as_bytes());
+        html
+    });
+
+    let mut memory_cache = MEMORY_CACHE.lock().unwrap();
+    if memory_cache.len() >= MEMORY_CACHE_SIZE { memory_cache.clear(); }
+    memory_cache.insert(key, html.clone());
+
+    html
+}
+
+// The same for binary renders too big to be worth keeping in memory (image thumbnails), so only
+// the disk layer is used. A render that fails isn't cached, and gets tried again next time.
+pub fn get_or_render_bytes(renderer: &str, options: &str, source: &str, render: impl FnOnce() -> Option<Vec<u8>>) -> Option<Vec<u8>> {
+    let key = cache_key(renderer, options, source);
+
+    if let Some(bytes) = read_disk(&DISK_CACHE, &key) {
+        return Some(bytes);
+    }
+
+    let bytes = render()?;
+    write_disk(&DISK_CACHE, &key, &bytes);
+    Some(bytes)
+}
+
+// "katex", "display", "x^2" -> "katex-3a1f...". The renderer name is kept readable in the file
+// name, mostly so it's easy to wipe a single renderer's output by hand.
+fn cache_key(renderer: &str, options: &str, source: &str) -> String {
+    versioned_key(&format!("{}/{}", FORMAT_VERSION, env!("CARGO_PKG_VERSION")), renderer, options, source)
+}
+
+fn versioned_key(version: &str, renderer: &str, options: &str, source: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [version, renderer, options, source] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    let hash = hasher.finalize().iter().take(16).map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}", renderer, hash)
+}
+
+fn cache_dir(disk: &Mutex<Option<DiskCache>>) -> Option<PathBuf> {
+    disk.lock().unwrap().as_ref().map(|disk_cache| disk_cache.dir.clone())
+}
+
+fn read_disk(disk: &Mutex<Option<DiskCache>>, key: &str) -> Option<Vec<u8>> {
+    let path = cache_dir(disk)?.join(key);
+
+    let bytes = fs::read(&path).ok()?;
+
+    // bump the mtime, so eviction is least-recently-used rather than least-recently-written
+    if let Ok(file) = fs::File::options().append(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+
+    Some(bytes)
+}
+
+fn write_disk(disk: &Mutex<Option<DiskCache>>, key: &str, bytes: &[u8]) {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let Some(dir) = cache_dir(disk) else { return; };
+
+    // write then rename, so a reader never sees a half written file (and with a name of its own,
+    // in case another thread is writing the same key)
+    let path = dir.join(key);
+    let tmp = dir.join(format!("{}.{}.tmp", key, COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let replaced = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    if fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, &path)).is_err() {
+        let _ = fs::remove_file(&tmp);
+        return;
+    }
+
+    let evicting = {
+        let mut disk_cache = disk.lock().unwrap();
+        let Some(disk_cache) = disk_cache.as_mut() else { return; };
+        disk_cache.size = disk_cache.size.saturating_sub(replaced) + bytes.len() as u64;
+        let evicting = disk_cache.size > disk_cache.limit && !disk_cache.evicting;
+        disk_cache.evicting |= evicting;
+        evicting
+    };
+    if evicting { evict(disk, &dir); }
+}
+
+fn evict(disk: &Mutex<Option<DiskCache>>, dir: &Path) {
+    let Some(limit) = disk.lock().unwrap().as_ref().map(|disk_cache| disk_cache.limit) else { return; };
+    let mut files = cache_files(dir);
+    files.sort_by_key(|(_, _, modified)| *modified);
+
+    let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+    let mut removed = 0;
+    for (path, len, _) in files {
+        if size <= limit / 4 * 3 { break; }
+        if fs::remove_file(&path).is_ok() { size -= len; removed += len; }
+    }
+
+    // (taking off what was removed, rather than setting it to what's left, keeps anything written
+    // in the meantime)
+    if let Some(disk_cache) = disk.lock().unwrap().as_mut() {
+        disk_cache.size = disk_cache.size.saturating_sub(removed);
+        disk_cache.evicting = false;
+    }
+}
+
+// (path, size, last modified) of every file in the cache directory
+fn cache_files(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(entries) = fs::read_dir(dir) else { return vec![]; };
+    entries.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let metadata = entry.metadata().ok()?;
+        if !metadata.is_file() { return None; }
+        Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+    }).collect()
+}
+
+#[test]
+fn disk_cache_test() {
+    let dir = std::env::temp_dir().join(format!("dungeon-cache-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let disk = Mutex::new(None);
+    init_disk(&disk, dir.clone(), 1000);
+    let size = || disk.lock().unwrap().as_ref().unwrap().size;
+
+    // writing over an entry replaces its size, rather than adding to it
+    write_disk(&disk, "a", &[0; 100]);
+    write_disk(&disk, "a", &[1; 200]);
+    assert_eq!(size(), 200);
+    assert_eq!(read_disk(&disk, "a"), Some(vec![1; 200]));
+
+    // once it's over the limit, the least recently used entries go until it's under 3/4 of it
+    for key in ["b", "c", "d"] { write_disk(&disk, key, &[0; 200]); }
+    for (key, age) in [("a", 0), ("b", 30), ("c", 20), ("d", 10)] {
+        let file = fs::File::options().append(true).open(dir.join(key)).unwrap();
+        file.set_modified(SystemTime::now() - std::time::Duration::from_secs(age)).unwrap();
+    }
+    write_disk(&disk, "e", &[0; 300]);
+    let mut left = cache_files(&dir).into_iter().map(|(path, ..)| path.file_name().unwrap().to_str().unwrap().to_string()).collect::<Vec<_>>();
+    left.sort();
+    assert_eq!(left, ["a", "d", "e"]);
+    assert_eq!(size(), 700);
+
+    // and anything saved by another version is never read back
+    let key = |version: &str| versioned_key(version, "katex", "display", "x^2");
+    write_disk(&disk, &key("0.0.1"), b"<span>old</span>");
+    assert_ne!(key("0.0.1"), cache_key("katex", "display", "x^2"));
+    assert_eq!(read_disk(&disk, &cache_key("katex", "display", "x^2")), None);
+    assert_eq!(read_disk(&disk, &key("0.0.1")).as_deref(), Some(&b"<span>old</span>"[..]));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
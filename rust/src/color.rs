@@ -0,0 +1,219 @@
+use elm_rs::{Elm, ElmDecode, ElmEncode};
+use serde::{Deserialize, Serialize};
+
+// A color as it was written in the file ("#fc0", "rgb(255 200 0 / 50%)", "tomato", "accent"),
+// along with what the page should actually draw for it. Only the page's version ever makes it into
+// any css or svg, and it's always plain hex - so whatever's written in the file, nothing past the
+// color itself can get through. The written form is what's saved back, so a color round-trips
+// spelled the way it was typed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct Color {
+    pub written: String,
+    pub css: String, // "#rrggbb", or "#rrggbbaa" when it isn't opaque. Empty until a name is resolved.
+}
+
+// what's drawn for a color that couldn't be worked out, alongside a diagnostic saying why
+pub const INVALID: &str = "#00000000";
+
+impl Color {
+    // Hex (3, 4, 6 or 8 digits), rgb() / rgba() and hsl() / hsla() are turned into css straight
+    // away. A bare name might be one of the document's palette entries, which aren't known until
+    // the whole thing's been read, so it's left unresolved (see resolve). None if it's neither.
+    pub fn parse(value: &str) -> Option<Color> {
+        let written = value.to_string();
+        let value = value.trim().to_lowercase();
+
+        let css = if let Some(hex) = value.strip_prefix('#') {
+            parse_hex(hex)?
+        } else if let Some(arguments) = function_arguments(&value, &["rgba", "rgb"]) {
+            parse_rgb(&arguments)?
+        } else if let Some(arguments) = function_arguments(&value, &["hsla", "hsl"]) {
+            parse_hsl(&arguments)?
+        } else if is_name(&value) {
+            String::new()
+        } else {
+            return None;
+        };
+
+        Some(Color { written, css })
+    }
+
+    // The lowercased name, for a color that's waiting on one.
+    pub fn name(&self) -> Option<String> {
+        if !self.css.is_empty() { return None; }
+        Some(self.written.trim().to_lowercase())
+    }
+
+    // Look the name up in the palette, and then among css's own named colors. False (and drawn as
+    // INVALID) if it isn't in either.
+    pub fn resolve(&mut self, palette: &std::collections::BTreeMap<String, Color>) -> bool {
+        let Some(name) = self.name() else { return true; };
+        match palette.get(&name).map(|color| color.css.clone()).or_else(|| named(&name).map(str::to_string)) {
+            Some(css) => { self.css = css; true },
+            None => { self.css = INVALID.to_string(); false },
+        }
+    }
+}
+
+fn is_name(value: &str) -> bool {
+    value.starts_with(|c: char| c.is_ascii_alphabetic()) && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// "rgb(1, 2, 3)" => ["1", "2", "3"], and "rgb(1 2 3 / 50%)" => ["1", "2", "3", "50%"]
+fn function_arguments(value: &str, names: &[&str]) -> Option<Vec<String>> {
+    let name = names.iter().find(|name| value.starts_with(*name))?;
+    let inside = value[name.len()..].trim_start().strip_prefix('(')?.strip_suffix(')')?;
+
+    let arguments = if inside.contains(',') {
+        inside.split(',').map(|argument| argument.trim().to_string()).collect::<Vec<_>>()
+    } else {
+        let (channels, alpha) = match inside.split_once('/') {
+            Some((channels, alpha)) => (channels, Some(alpha.trim())),
+            None => (inside, None),
+        };
+        channels.split_whitespace().chain(alpha).map(str::to_string).collect()
+    };
+    Some(arguments)
+}
+
+fn parse_hex(hex: &str) -> Option<String> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) { return None; }
+    let long = match hex.len() {
+        3 | 4 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 | 8 => hex.to_string(),
+        _ => return None,
+    };
+    Some(format!("#{}", long.strip_suffix("ff").filter(|_| long.len() == 8).unwrap_or(&long)))
+}
+
+fn parse_rgb(arguments: &[String]) -> Option<String> {
+    let (channels, alpha) = split_alpha(arguments)?;
+    let channel = |value: &str| match value.strip_suffix('%') {
+        Some(percent) => number(percent, 0.0, 100.0).map(|percent| percent * 2.55),
+        None => number(value, 0.0, 255.0),
+    };
+    Some(hex([channel(channels[0])?, channel(channels[1])?, channel(channels[2])?], alpha))
+}
+
+fn parse_hsl(arguments: &[String]) -> Option<String> {
+    let (channels, alpha) = split_alpha(arguments)?;
+    let hue = channels[0].strip_suffix("deg").unwrap_or(channels[0]).trim().parse::<f64>().ok().filter(|hue| hue.is_finite())?;
+    let percent = |value: &str| number(value.strip_suffix('%')?, 0.0, 100.0).map(|percent| percent / 100.0);
+    let (saturation, lightness) = (percent(channels[1])?, percent(channels[2])?);
+
+    // from the css spec
+    let f = |n: f64| {
+        let k = (n + hue.rem_euclid(360.0) / 30.0) % 12.0;
+        let a = saturation * lightness.min(1.0 - lightness);
+        255.0 * (lightness - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0))
+    };
+    Some(hex([f(0.0), f(8.0), f(4.0)], alpha))
+}
+
+// the three channels, and an alpha from 0 to 1 (1 if there isn't one)
+fn split_alpha(arguments: &[String]) -> Option<([&str; 3], f64)> {
+    let alpha = match arguments.get(3).map(String::as_str) {
+        None => 1.0,
+        Some(alpha) => match alpha.strip_suffix('%') {
+            Some(percent) => number(percent, 0.0, 100.0)? / 100.0,
+            None => number(alpha, 0.0, 1.0)?,
+        },
+    };
+    match arguments {
+        [r, g, b] | [r, g, b, _] => Some(([r, g, b], alpha)),
+        _ => None,
+    }
+}
+
+fn number(value: &str, min: f64, max: f64) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|number| (min..=max).contains(number))
+}
+
+fn hex(channels: [f64; 3], alpha: f64) -> String {
+    let byte = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+    let [r, g, b] = channels.map(byte);
+    match byte(alpha * 255.0) {
+        255 => format!("#{:02x}{:02x}{:02x}", r, g, b),
+        a => format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a),
+    }
+}
+
+// css's named colors, as hex
+pub fn named(name: &str) -> Option<&'static str> {
+    NAMED.binary_search_by_key(&name, |&(name, _)| name).ok().map(|i| NAMED[i].1)
+}
+
+// sorted, for the binary search
+const NAMED: [(&str, &str); 149] = [
+    ("aliceblue", "#f0f8ff"), ("antiquewhite", "#faebd7"), ("aqua", "#00ffff"), ("aquamarine", "#7fffd4"),
+    ("azure", "#f0ffff"), ("beige", "#f5f5dc"), ("bisque", "#ffe4c4"), ("black", "#000000"),
+    ("blanchedalmond", "#ffebcd"), ("blue", "#0000ff"), ("blueviolet", "#8a2be2"), ("brown", "#a52a2a"),
+    ("burlywood", "#deb887"), ("cadetblue", "#5f9ea0"), ("chartreuse", "#7fff00"), ("chocolate", "#d2691e"),
+    ("coral", "#ff7f50"), ("cornflowerblue", "#6495ed"), ("cornsilk", "#fff8dc"), ("crimson", "#dc143c"),
+    ("cyan", "#00ffff"), ("darkblue", "#00008b"), ("darkcyan", "#008b8b"), ("darkgoldenrod", "#b8860b"),
+    ("darkgray", "#a9a9a9"), ("darkgreen", "#006400"), ("darkgrey", "#a9a9a9"), ("darkkhaki", "#bdb76b"),
+    ("darkmagenta", "#8b008b"), ("darkolivegreen", "#556b2f"), ("darkorange", "#ff8c00"), ("darkorchid", "#9932cc"),
+    ("darkred", "#8b0000"), ("darksalmon", "#e9967a"), ("darkseagreen", "#8fbc8f"), ("darkslateblue", "#483d8b"),
+    ("darkslategray", "#2f4f4f"), ("darkslategrey", "#2f4f4f"), ("darkturquoise", "#00ced1"), ("darkviolet", "#9400d3"),
+    ("deeppink", "#ff1493"), ("deepskyblue", "#00bfff"), ("dimgray", "#696969"), ("dimgrey", "#696969"),
+    ("dodgerblue", "#1e90ff"), ("firebrick", "#b22222"), ("floralwhite", "#fffaf0"), ("forestgreen", "#228b22"),
+    ("fuchsia", "#ff00ff"), ("gainsboro", "#dcdcdc"), ("ghostwhite", "#f8f8ff"), ("gold", "#ffd700"),
+    ("goldenrod", "#daa520"), ("gray", "#808080"), ("green", "#008000"), ("greenyellow", "#adff2f"),
+    ("grey", "#808080"), ("honeydew", "#f0fff0"), ("hotpink", "#ff69b4"), ("indianred", "#cd5c5c"),
+    ("indigo", "#4b0082"), ("ivory", "#fffff0"), ("khaki", "#f0e68c"), ("lavender", "#e6e6fa"),
+    ("lavenderblush", "#fff0f5"), ("lawngreen", "#7cfc00"), ("lemonchiffon", "#fffacd"), ("lightblue", "#add8e6"),
+    ("lightcoral", "#f08080"), ("lightcyan", "#e0ffff"), ("lightgoldenrodyellow", "#fafad2"), ("lightgray", "#d3d3d3"),
+    ("lightgreen", "#90ee90"), ("lightgrey", "#d3d3d3"), ("lightpink", "#ffb6c1"), ("lightsalmon", "#ffa07a"),
+    ("lightseagreen", "#20b2aa"), ("lightskyblue", "#87cefa"), ("lightslategray", "#778899"), ("lightslategrey", "#778899"),
+    ("lightsteelblue", "#b0c4de"), ("lightyellow", "#ffffe0"), ("lime", "#00ff00"), ("limegreen", "#32cd32"),
+    ("linen", "#faf0e6"), ("magenta", "#ff00ff"), ("maroon", "#800000"), ("mediumaquamarine", "#66cdaa"),
+    ("mediumblue", "#0000cd"), ("mediumorchid", "#ba55d3"), ("mediumpurple", "#9370db"), ("mediumseagreen", "#3cb371"),
+    ("mediumslateblue", "#7b68ee"), ("mediumspringgreen", "#00fa9a"), ("mediumturquoise", "#48d1cc"), ("mediumvioletred", "#c71585"),
+    ("midnightblue", "#191970"), ("mintcream", "#f5fffa"), ("mistyrose", "#ffe4e1"), ("moccasin", "#ffe4b5"),
+    ("navajowhite", "#ffdead"), ("navy", "#000080"), ("oldlace", "#fdf5e6"), ("olive", "#808000"),
+    ("olivedrab", "#6b8e23"), ("orange", "#ffa500"), ("orangered", "#ff4500"), ("orchid", "#da70d6"),
+    ("palegoldenrod", "#eee8aa"), ("palegreen", "#98fb98"), ("paleturquoise", "#afeeee"), ("palevioletred", "#db7093"),
+    ("papayawhip", "#ffefd5"), ("peachpuff", "#ffdab9"), ("peru", "#cd853f"), ("pink", "#ffc0cb"),
+    ("plum", "#dda0dd"), ("powderblue", "#b0e0e6"), ("purple", "#800080"), ("rebeccapurple", "#663399"),
+    ("red", "#ff0000"), ("rosybrown", "#bc8f8f"), ("royalblue", "#4169e1"), ("saddlebrown", "#8b4513"),
+    ("salmon", "#fa8072"), ("sandybrown", "#f4a460"), ("seagreen", "#2e8b57"), ("seashell", "#fff5ee"),
+    ("sienna", "#a0522d"), ("silver", "#c0c0c0"), ("skyblue", "#87ceeb"), ("slateblue", "#6a5acd"),
+    ("slategray", "#708090"), ("slategrey", "#708090"), ("snow", "#fffafa"), ("springgreen", "#00ff7f"),
+    ("steelblue", "#4682b4"), ("tan", "#d2b48c"), ("teal", "#008080"), ("thistle", "#d8bfd8"),
+    ("tomato", "#ff6347"), ("transparent", "#00000000"), ("turquoise", "#40e0d0"), ("violet", "#ee82ee"),
+    ("wheat", "#f5deb3"), ("white", "#ffffff"), ("whitesmoke", "#f5f5f5"), ("yellow", "#ffff00"),
+    ("yellowgreen", "#9acd32"),
+];
+
+#[test]
+fn color_test() {
+    assert!(NAMED.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    let css = |value: &str| Color::parse(value).map(|color| color.css);
+    assert_eq!(css("#FC0"), Some("#ffcc00".to_string()));
+    assert_eq!(css("#ffcc0080"), Some("#ffcc0080".to_string()));
+    assert_eq!(css("#fc0f"), Some("#ffcc00".to_string()));
+    assert_eq!(css("rgb(255, 204, 0)"), Some("#ffcc00".to_string()));
+    assert_eq!(css("rgba(100%, 80%, 0%, 0.5)"), Some("#ffcc0080".to_string()));
+    assert_eq!(css("rgb(255 204 0 / 50%)"), Some("#ffcc0080".to_string()));
+    assert_eq!(css("hsl(48deg, 100%, 50%)"), Some("#ffcc00".to_string()));
+    assert_eq!(css("hsla(-312, 100%, 50%, 1)"), Some("#ffcc00".to_string()));
+
+    // names wait until the palette's known
+    let mut accent = Color::parse("Accent").unwrap();
+    assert_eq!((accent.written.as_str(), accent.name().as_deref()), ("Accent", Some("accent")));
+    let palette = [("accent".to_string(), Color::parse("#10141f").unwrap())].into();
+    assert!(accent.resolve(&palette));
+    assert_eq!(accent.css, "#10141f");
+
+    let mut tomato = Color::parse("tomato").unwrap();
+    assert!(tomato.resolve(&palette));
+    assert_eq!(tomato.css, "#ff6347");
+    let mut unknown = Color::parse("nonsense").unwrap();
+    assert!(!unknown.resolve(&palette));
+    assert_eq!(unknown.css, INVALID);
+
+    for value in ["#12345", "#ggg", "rgb(256, 0, 0)", "rgb(1, 2)", "hsl(0, 50, 50%)", "red;position:fixed", "url(x)", "var(--x)", ""] {
+        assert_eq!(Color::parse(value), None, "{} was allowed", value);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Just enough of each image format to pull its size out of the header, so the page can lay an
+// image out at the right size before it's loaded. Anything that isn't a png, gif, jpeg or webp (or
+// is too broken to tell) is None, and the browser works it out the slow way.
+pub fn dimensions(path: &Path) -> Option<(u32, u32)> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+
+    let mut header = Vec::with_capacity(30);
+    file.by_ref().take(30).read_to_end(&mut header).ok()?;
+    if header.len() < 30 { return None; }
+
+    let be32 = |i: usize| u32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    let le16 = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as u32;
+    let le24 = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], 0]);
+
+    match &header[..] {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', _, _, _, _, b'I', b'H', b'D', b'R', ..] => Some((be32(16), be32(20))),
+
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some((le16(6), le16(8))),
+
+        [0xff, 0xd8, ..] => jpeg_dimensions(&mut file),
+
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', b'V', b'P', b'8', kind, ..] => match kind {
+            b' ' => Some((le16(26) & 0x3fff, le16(28) & 0x3fff)),
+            // lossless packs them into 14 bits each, minus one
+            b'L' => {
+                let bits = u32::from_le_bytes([header[21], header[22], header[23], header[24]]);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            },
+            b'X' => Some((le24(24) + 1, le24(27) + 1)),
+            _ => None,
+        },
+
+        _ => None,
+    }
+}
+
+// The usual file extension for an image in one of those formats, from its first few bytes.
+pub fn extension(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("png"),
+        [0xff, 0xd8, 0xff, ..] => Some("jpg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        _ => None,
+    }
+}
+
+// A jpeg's size is in its start of frame segment, which can come after any amount of exif data,
+// thumbnails and so on. Skip from segment to segment until we find it.
+fn jpeg_dimensions(file: &mut (impl Read + Seek)) -> Option<(u32, u32)> {
+    file.seek(SeekFrom::Start(2)).ok()?;
+
+    loop {
+        let mut marker = [0; 2];
+        file.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xff { return None; }
+
+        // any number of 0xff bytes can pad out the space between segments
+        if marker[1] == 0xff {
+            file.seek(SeekFrom::Current(-1)).ok()?;
+            continue;
+        }
+
+        let mut length = [0; 2];
+        file.read_exact(&mut length).ok()?;
+        let length = u16::from_be_bytes(length) as i64;
+
+        match marker[1] {
+            // every SOFn marker, except the three in that range that mean something else
+            0xc0..=0xcf if !matches!(marker[1], 0xc4 | 0xc8 | 0xcc) => {
+                let mut frame = [0; 5]; // precision, height, width
+                file.read_exact(&mut frame).ok()?;
+                let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+                let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+                return Some((width, height));
+            },
+            _ => { file.seek(SeekFrom::Current(length - 2)).ok()?; },
+        }
+    }
+}
+
+#[test]
+fn dimensions_test() {
+    let dir = std::env::temp_dir().join(format!("dungeon-image-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dimensions_of = |name: &str, bytes: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        dimensions(&path)
+    };
+
+    let png = [&b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"[..], &640u32.to_be_bytes(), &480u32.to_be_bytes(), &[0; 8]].concat();
+    assert_eq!(dimensions_of("a.png", &png), Some((640, 480)));
+
+    let gif = [&b"GIF89a"[..], &[0x20, 0x03, 0x58, 0x02], &[0; 20]].concat();
+    assert_eq!(dimensions_of("a.gif", &gif), Some((800, 600)));
+
+    // an exif segment to skip over, then the frame
+    let jpeg = [&[0xff, 0xd8, 0xff, 0xe1, 0x00, 0x06][..], b"Exif", &[0xff, 0xff, 0xc0, 0x00, 0x11, 0x08, 0x01, 0x2c, 0x01, 0x90], &[0; 20]].concat();
+    assert_eq!(dimensions_of("a.jpg", &jpeg), Some((400, 300)));
+
+    let webp = [&b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0\0\0\0"[..], &[0xff, 0x03, 0x00, 0x1f, 0x01, 0x00], &[0; 4]].concat();
+    assert_eq!(dimensions_of("a.webp", &webp), Some((1024, 288)));
+
+    assert_eq!(dimensions_of("a.txt", b"not an image, just some text in a file"), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,23 +1,36 @@
 #![feature(let_chains)]
+#![feature(file_set_times)]
+#![feature(test)]
 
-use warp::{sse, Filter};
+#[cfg(test)]
+extern crate test;
+
+use warp::{sse, Filter, Reply};
 
 use async_stream::stream;
 
 use lazy_static::lazy_static;
 
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::fs::File;
 use std::io::prelude::*;
 
 use notify::RecursiveMode;
 use notify_debouncer_mini::new_debouncer;
 
+use sha2::{Digest, Sha256};
+
+mod cache;
+mod color;
+mod image;
 mod parser;
-use parser::{Document, DocumentUpdate, Element, TextBlock, TextChunk};
+mod thumbnail;
+use color::Color;
+use parser::{Crop, DefinitionItem, Diagnostic, Document, DocumentUpdate, Element, InkPoint, ListDelimiter, ListStyle, Markdown, Reorder, Shape, ShapePoint, StrokeStyle, TextAlign, TextBlock, TextChunk, TextStyle};
 
 // -- document data ------------------------------------------------------------
 
@@ -27,9 +40,19 @@ lazy_static! {
     // get the root path of the document, minus the file itself
     static ref D1: Vec<u8> = DOC_PATH.bytes().rev().skip_while(|&b| b != b'/').collect::<Vec<u8>>();
     static ref D2: Vec<u8> = D1.clone().into_iter().rev().collect::<Vec<u8>>();
-    static ref D3: String = std::str::from_utf8(&*D2).unwrap().to_string();
+    static ref D3: String = std::str::from_utf8(&D2).unwrap().to_string();
 
     static ref FRONT_PATH: String = std::env::args().nth(2).unwrap_or("".to_string());
+
+    // where uploaded images go, relative to the document. Set with --assets=<folder>
+    static ref ASSETS: String = {
+        let assets = std::env::args().find_map(|arg| arg.strip_prefix("--assets=").map(|s| s.trim_matches('/').to_string()));
+        let assets = assets.unwrap_or("assets".to_string());
+        if assets.is_empty() || assets.split('/').any(|segment| segment == "..") {
+            panic!("invalid assets folder: {}", assets);
+        }
+        assets
+    };
     static ref DOCUMENT: Arc<Mutex<Document>> = Arc::new(Mutex::new(Document::new()));
 
     // quick and dirty cross-thread signalling, by polling a bool to see if
@@ -56,8 +79,11 @@ fn load_document() {
 
     let text = std::fs::read_to_string(&*DOC_PATH).unwrap();
 
-    let parsed = parser::parse(&text);
+    let start = Instant::now();
+    let mut parsed = parser::parse(&text);
 
+    // bring along lines attached to anything that was moved by hand, and save them where they went
+    let reattached = parser::reattach(&document, &mut parsed);
     *document = parsed;
 
     // for (key, element) in document.elements.iter() {
@@ -66,7 +92,10 @@ fn load_document() {
     //     }
     // }
 
-    println!("Loaded from disk: {}", &*DOC_PATH);
+    println!("Loaded from disk: {} (parsed in {:.1?})", &*DOC_PATH, start.elapsed());
+
+    drop(document);
+    if reattached { save_document(); }
 }
 
 fn save_document() {
@@ -81,9 +110,7 @@ fn save_document() {
 
     let mut file = File::create(&*DOC_PATH).unwrap();
 
-    document.elements.values().for_each(|element| {
-        file.write(element.write_repr().as_bytes()).unwrap();
-    });
+    file.write_all(parser::write(&document).as_bytes()).unwrap();
 
     // set WATCH_BLOCK_CHECK to current + 1 second
     *watch_block_check = SystemTime::now() + Duration::from_secs(1);
@@ -91,6 +118,89 @@ fn save_document() {
 
 
 
+// Save an uploaded image into the assets folder, named by its hash so uploading the same
+// screenshot twice doesn't make two copies, and add it to the end of the target text box (or a new
+// one at x, y). Returns the image's path relative to the document.
+fn upload_image(bytes: &[u8], target: Option<&String>, x: f64, y: f64) -> Result<String, warp::http::StatusCode> {
+    let name = upload_name(bytes).ok_or(warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+    let relative = format!("{}/{}", &*ASSETS, name);
+
+    let markdown = format!("![]({})\n", relative);
+
+    // (before taking the document, so a full disk or the like can't leave it poisoned)
+    let path = Path::new(&*D3).join(&relative);
+    if !path.exists() {
+        std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&path, bytes))
+            .map_err(|e| { println!("couldn't save upload: {}", e); warp::http::StatusCode::INTERNAL_SERVER_ERROR })?;
+    }
+
+    {
+        let mut document = DOCUMENT.lock().unwrap();
+
+        if let Some(key) = target && !matches!(document.elements.get(key), Some(Element::TextBox { .. })) {
+            return Err(warp::http::StatusCode::NOT_FOUND);
+        }
+
+        match target.and_then(|key| document.elements.get_mut(key)) {
+            Some(Element::TextBox { raw_content, .. }) => {
+                if !raw_content.is_empty() && !raw_content.ends_with('\n') { raw_content.push('\n'); }
+                raw_content.push_str(&markdown);
+            },
+
+            // elements are saved in key order, and "~" comes after all the generated ones
+            _ => { document.elements.insert("~upload".to_string(), Element::text_box(x, y, markdown)); },
+        }
+    }
+
+    // Re-parse from the file we just wrote, so the new image goes through exactly the same path as
+    // anything typed in by hand, then let the page know to fetch it.
+    save_document();
+    load_document();
+    *DOCUMENT_REFRESHED.lock().unwrap() = true;
+
+    Ok(relative)
+}
+
+// "<first 16 bytes of the sha256, in hex>.<extension>", or None if it isn't an image we know.
+fn upload_name(bytes: &[u8]) -> Option<String> {
+    let extension = image::extension(bytes)?;
+    let hash = Sha256::digest(bytes).iter().take(16).map(|b| format!("{:02x}", b)).collect::<String>();
+    Some(format!("{}.{}", hash, extension))
+}
+
+// Whether a request's Origin header (if it has one) is a page served from this machine, so some other
+// site open in the browser can't post to us.
+fn local_origin(origin: Option<&str>) -> bool {
+    let Some(origin) = origin else { return true; };
+    let Some(host) = origin.strip_prefix("http://") else { return false; };
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    ["localhost", "127.0.0.1", "[::1]"].contains(&host)
+}
+
+#[test]
+fn upload_test() {
+    // the page itself, and anything that isn't a browser, can upload - other sites can't
+    assert!(local_origin(Some("http://localhost:3100")));
+    assert!(local_origin(Some("http://127.0.0.1:8000")));
+    assert!(local_origin(None));
+    assert!(!local_origin(Some("http://evil.example")));
+    assert!(!local_origin(Some("http://localhost.evil.example:3100")));
+    assert!(!local_origin(Some("https://localhost@evil.example")));
+    assert!(!local_origin(Some("null")));
+
+    // the same image is always saved under the same name, with the extension its bytes say it has
+    let png = [&b"\x89PNG\r\n\x1a\n"[..], &[0; 22]].concat();
+    assert_eq!(upload_name(&png).as_deref(), Some("26c113fb5358229fc8f1d84af373ca08.png"));
+    assert!(upload_name(b"GIF89a and the rest").unwrap().ends_with(".gif"));
+    assert_eq!(upload_name(b"not an image"), None);
+}
+
+
+
 // -- main ---------------------------------------------------------------------
 
 #[tokio::main]
@@ -105,8 +215,8 @@ async fn main() {
         ).unwrap();
 
         elm_rs::export!("Bindings", &mut target, {
-            encoders: [Document, Element, TextBlock, TextChunk, DocumentUpdate],
-            decoders: [Document, Element, TextBlock, TextChunk, DocumentUpdate],
+            encoders: [Document, Diagnostic, Element, Color, StrokeStyle, Shape, ShapePoint, Crop, InkPoint, Markdown, TextStyle, TextAlign, TextBlock, ListStyle, ListDelimiter, DefinitionItem, TextChunk, DocumentUpdate],
+            decoders: [Document, Diagnostic, Element, Color, StrokeStyle, Shape, ShapePoint, Crop, InkPoint, Markdown, TextStyle, TextAlign, TextBlock, ListStyle, ListDelimiter, DefinitionItem, TextChunk, DocumentUpdate],
         }).unwrap();
 
         return;
@@ -115,6 +225,13 @@ async fn main() {
 
     // -- watch file, reload on change -----------------------------------------
 
+    cache::init(Path::new(&*D3).join(".dungeon/cache"));
+
+    parser::configure(parser::Options {
+        root: Path::new(&*D3).to_path_buf(),
+        flag_unsafe_urls: std::env::args().any(|arg| arg == "--flag-unsafe-urls"),
+    });
+
     load_document(); // load it once at the start
 
     std::thread::spawn(|| {
@@ -133,8 +250,8 @@ async fn main() {
             match rx.recv() {
                 Ok(_) => {
 
-                    let watch_block_check = WATCH_BLOCK_CHECK.lock().unwrap();
-                    if SystemTime::now() < *watch_block_check { continue; }
+                    // (not held past here, since loading can save)
+                    if SystemTime::now() < *WATCH_BLOCK_CHECK.lock().unwrap() { continue; }
 
                     load_document();
                     *DOCUMENT_REFRESHED.lock().unwrap() = true;
@@ -150,13 +267,70 @@ async fn main() {
 
     // GET / => front_path/index.html
     let front = warp::path::end().and(warp::fs::file(FRONT_PATH.clone() + "/index.html"));
-    // GET /fetch => send json encoded document
-    let fetch = warp::path("fetch").map(|| warp::reply::json(&*DOCUMENT.lock().unwrap()));
+    // GET /fetch => send json encoded document, without any hidden elements unless ?drafts is set
+    let fetch = warp::path("fetch").and(warp::query::<HashMap<String, String>>()).map(|query: HashMap<String, String>| {
+        let document = DOCUMENT.lock().unwrap();
+        if query.get("drafts").is_some_and(|drafts| drafts != "false") {
+            return warp::reply::json(&*document);
+        }
+        warp::reply::json(&Document {
+            elements: document.elements.iter().filter(|(_, element)| !element.hidden()).map(|(key, element)| (key.clone(), element.clone())).collect(),
+            palette: document.palette.clone(),
+            diagnostics: document.diagnostics.clone(),
+            created: document.created,
+            palette_after: None,
+        })
+    });
 
     // GET /<path> => if front_path/<path> exists, send it, otherwise
     //                serve static file starting from the same root as DOC_PATH
     let static_files = warp::fs::dir(FRONT_PATH.clone() + "/").or(warp::fs::dir(D3.clone()));
 
+    // GET /thumbnail/<width>/<path> => a downscaled copy of the image at /<path>, or a redirect to
+    //                                  the original if we can't make one (resizing is slow, so it's
+    //                                  done off of the async threads)
+    let thumbnail = warp::path!("thumbnail" / u32 / ..).and(warp::path::tail()).then(|width: u32, path: warp::path::Tail| async move {
+        let original = format!("/{}", path.as_str());
+        let file = parser::local_file(&original);
+        let source = file.clone();
+        let bytes = tokio::task::spawn_blocking(move || source.and_then(|source| thumbnail::thumbnail(&source, width))).await.ok().flatten();
+        match bytes {
+            Some(bytes) => warp::http::Response::builder()
+                .header("content-type", thumbnail::content_type(file.as_ref().unwrap()))
+                .body(bytes)
+                .unwrap()
+                .into_response(),
+            None => warp::redirect::temporary(original.parse::<warp::http::Uri>().unwrap_or_default()).into_response(),
+        }
+    });
+
+    // POST /upload?target=<id> or /upload?x=<x>&y=<y> => save the image in the body to the assets
+    //                                                    folder, and add it to the target text box
+    //                                                    (or a new one at x, y)
+    let upload = warp::path("upload").and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::body::content_length_limit(64 * 1024 * 1024))
+        .and(warp::body::bytes())
+        .then(|query: HashMap<String, String>, origin: Option<String>, bytes: warp::hyper::body::Bytes| async move {
+            if !local_origin(origin.as_deref()) {
+                return warp::reply::with_status(warp::reply(), warp::http::StatusCode::FORBIDDEN).into_response();
+            }
+            let coordinate = |name: &str| query.get(name).and_then(|value| value.parse().ok()).unwrap_or(0.0);
+            let (target, x, y) = (query.get("target").cloned(), coordinate("x"), coordinate("y"));
+
+            // saving and re-parsing the whole document takes a while, so it's done off of the async threads
+            let uploaded = tokio::task::spawn_blocking(move || upload_image(&bytes, target.as_ref(), x, y)).await
+                .unwrap_or(Err(warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+            match uploaded {
+                Ok(path) => {
+                    println!("uploaded: {}", path);
+                    warp::reply::json(&path).into_response()
+                },
+                Err(status) => warp::reply::with_status(warp::reply(), status).into_response(),
+            }
+        });
+
     // POST /update/<id> => update document with json encoded Text
     let update = warp::path!("update" / String).and(warp::body::json()).map(
         |key: String, update: DocumentUpdate| {
@@ -171,14 +345,26 @@ async fn main() {
 
                 print!("updating: {}...", key);
 
+                if let Some(old_element) = document.elements.get(&key) && old_element.locked() && !old_element.same_geometry(&update.element) {
+                    println!("locked");
+                    return warp::reply::with_status(warp::reply(), warp::http::StatusCode::FORBIDDEN);
+                }
+
                 // if we're updating an already existing element from Element::TextBox to Element::TextBox,
-                // then keep the raw_content field the same (while replacing everything else).
-                // Otherwise, just replace the whole element.
+                // then keep the raw_content field the same (while replacing everything else), and
+                // likewise for the recorded strokes of Element::Ink. Otherwise, just replace the whole element.
 
                 let new_element = // weirdly hard to make this code better, yada yada borrow checker
-                    if let Some(Element::TextBox { raw_content, .. }) = document.elements.get(&key) {
-                        if let Element::TextBox { x, y, width, data, raw_content: _ } = update.element {
-                            Element::TextBox { x, y, width, data, raw_content: raw_content.clone() }
+                    if let Some(Element::TextBox { raw_content, style: old_style, .. }) = document.elements.get(&key) {
+                        if let Element::TextBox { x, y, width, z, rotation, id, parent, locked, hidden, mut style, markdown, data, raw_content: _ } = update.element {
+                            style.rejected = old_style.rejected.clone(); // (which the page never sees)
+                            Element::TextBox { x, y, width, z, rotation, id, parent, locked, hidden, style, markdown, data, raw_content: raw_content.clone() }
+                        } else {
+                            update.element
+                        }
+                    } else if let Some(Element::Ink { recorded, raw_content, .. }) = document.elements.get(&key) {
+                        if let Element::Ink { x, y, z, color, width, strokes, recorded: _, raw_content: _, parent, locked, hidden } = update.element {
+                            Element::Ink { x, y, z, color, width, strokes, recorded: recorded.clone(), raw_content: raw_content.clone(), parent, locked, hidden }
                         } else {
                             update.element
                         }
@@ -186,7 +372,24 @@ async fn main() {
                         update.element
                     };
 
+                // and never trust anything from the page that doesn't come from the file the same way
+                let (new_element, diagnostics) = match parser::check_update(&new_element, &document.palette) {
+                    Ok(checked) => checked,
+                    Err(e) => {
+                        println!("refused: {}", e);
+                        return warp::reply::with_status(warp::reply(), warp::http::StatusCode::BAD_REQUEST);
+                    },
+                };
+                document.diagnostics.retain(|diagnostic| diagnostic.element != key);
+                document.diagnostics.extend(diagnostics.into_iter().map(|message| Diagnostic { element: key.clone(), message }));
+
+                // drag along any lines attached to it (and if it's a frame, everything in it), all
+                // in the same save, and let the page know they've moved
+                let moved = parser::move_dependents(&mut document.elements, &key, &new_element);
+
                 document.elements.insert(key, new_element);
+
+                if moved { *DOCUMENT_REFRESHED.lock().unwrap() = true; }
             }
 
             save_document();
@@ -196,6 +399,32 @@ async fn main() {
             warp::reply::with_status(warp::reply(), warp::http::StatusCode::OK)
     } );
 
+    // POST /reorder/<id>/<forward|backward|front|back> => move an element up or down the page's
+    // stack of elements, renumbering whichever others it has to in the same write
+    let reorder = warp::path!("reorder" / String / String).and(warp::post())
+        .and(warp::header::optional::<String>("origin"))
+        .map(|key: String, direction: String, origin: Option<String>| {
+            if !local_origin(origin.as_deref()) { return warp::http::StatusCode::FORBIDDEN; }
+
+            let reorder = match direction.as_str() {
+                "forward" => Reorder::Forward,
+                "backward" => Reorder::Backward,
+                "front" => Reorder::Front,
+                "back" => Reorder::Back,
+                _ => return warp::http::StatusCode::BAD_REQUEST,
+            };
+
+            {
+                let mut document = DOCUMENT.lock().unwrap();
+                if !document.elements.contains_key(&key) { return warp::http::StatusCode::NOT_FOUND; }
+                if !parser::reorder(&mut document.elements, &key, reorder) { return warp::http::StatusCode::OK; }
+            }
+
+            save_document();
+            *DOCUMENT_REFRESHED.lock().unwrap() = true;
+            warp::http::StatusCode::OK
+        });
+
     // simple SSE event
     fn sse_event() -> Result<sse::Event, Infallible> {
         Ok(sse::Event::default().data(""))
@@ -215,7 +444,7 @@ async fn main() {
         warp::sse::reply(warp::sse::keep_alive().stream(stream))
     });
 
-    let routes = front.or(fetch).or(update).or(file_change_sse).or(static_files);
+    let routes = front.or(fetch).or(update).or(reorder).or(upload).or(thumbnail).or(file_change_sse).or(static_files);
 
 
 
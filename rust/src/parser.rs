@@ -1,6 +1,10 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, RwLock};
 use std::time::SystemTime;
 
 use lazy_static::lazy_static;
@@ -8,20 +12,33 @@ use lazy_static::lazy_static;
 use elm_rs::{Elm, ElmDecode, ElmEncode};
 use serde::{Deserialize, Serialize};
 
+use rayon::prelude::*;
+
 use regex::Regex;
 
-use prism_js::{init, highlight};
+use prism_js::{init, highlight, PrismContext};
+
+use crate::{cache, image, thumbnail};
+use crate::color::{self, Color};
 
 // --------------------------- types shared with elm ---------------------------
 
 #[derive(Debug, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
 pub struct Document {
     pub elements: BTreeMap<String, Element>,
+    pub palette: BTreeMap<String, Color>, // named colors, from "!!!!Palette!accent:#ff6b6b!!!!"
+    pub diagnostics: Vec<Diagnostic>,
     pub created: u64,
+
+    #[serde(skip)]
+    pub palette_after: Option<String>, // the key of the element the palette was read after, None if it was at the top
 }
 impl Document {
     pub fn new() -> Self { Self {
             elements: BTreeMap::new(),
+            palette: BTreeMap::new(),
+            diagnostics: Vec::new(),
+            palette_after: None,
             created: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
@@ -29,11 +46,52 @@ impl Document {
     } }
 }
 
-#[derive(Debug, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+// Something wrong with an element that didn't stop it from being parsed, shown on the page rather
+// than only printed to the server's terminal.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct Diagnostic {
+    pub element: String, // the id of the element it came from
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
 pub enum Element {
-    Line    { x1: f64, y1: f64, x2: f64, y2: f64, },
-    Rect    { x: f64, y: f64, width: f64, height: f64, z: i32, color: String, },
-    TextBox { x: f64, y: f64, width: f64,
+    Line    { x1: f64, y1: f64, x2: f64, y2: f64, z: i32,
+        color: Color,
+        width: f64, // of the stroke, in pixels
+        style: StrokeStyle,
+        start_arrow: bool, // "arrows:start", "arrows:end" or "arrows:both"
+        end_arrow: bool,
+        from: Option<String>, // the ids of the elements each end is attached to, so the ends move
+        to: Option<String>,   // along with them (see move_connections)
+        parent: Option<String>,
+        locked: bool,
+        hidden: bool,
+    },
+    Rect    { x: f64, y: f64, width: f64, height: f64, z: i32,
+        rotation: f64, // clockwise in degrees, around the top left corner (like all the others)
+        color: Color, // the fill
+        id: Option<String>,
+        shape: Shape,
+        stroke: Option<Color>, // the outline's color, None for no outline
+        stroke_width: f64,
+        style: StrokeStyle, // of the outline
+        opacity: f64, // of the whole shape, from 0 to 1
+        parent: Option<String>,
+        locked: bool,
+        hidden: bool,
+    },
+    TextBox { x: f64, y: f64, width: f64, z: i32, rotation: f64,
+
+        id: Option<String>, // for lines to attach to. Unlike the element's key, this is kept in the file.
+
+        parent: Option<String>, // the id of the frame it's in, which its position is relative to in the file
+        locked: bool, // can't be moved or resized, from the page or anywhere else
+        hidden: bool, // a draft, only shown with /?drafts
+
+        style: TextStyle, // how the box itself looks, see below
+
+        markdown: Markdown, // which flavour of markdown the contents are written in
 
         data: Vec<TextBlock>, // data is the parsed contents of the text box
 
@@ -42,54 +100,534 @@ pub enum Element {
                              // save it back to the file without regenerating it
                              // from a probably lossy form.
     },
+    ImageBox { x: f64, y: f64, width: f64, height: f64, z: i32, rotation: f64, id: Option<String>,
+        src: String, // as written in the file
+        url: String, // src after safe_url, which is what's actually shown. Empty if it isn't allowed.
+        crop: Option<Crop>,
+        parent: Option<String>,
+        locked: bool,
+        hidden: bool,
+    },
+    Ink     { x: f64, y: f64, z: i32,
+        color: Color,
+        width: f64, // of the strokes at full pressure
+        strokes: Vec<Vec<InkPoint>>, // simplified for drawing, relative to x and y
+
+        #[serde(skip)]
+        recorded: Vec<Vec<InkPoint>>, // every point as it was drawn, which is what's saved
+        #[serde(skip)]
+        raw_content: String, // the body as it was read, so strokes that haven't changed are saved the same way
+
+        parent: Option<String>,
+        locked: bool,
+        hidden: bool,
+    },
+    // A labelled region of the page. Anything inside it, or with "parent:<its id>", is moved along
+    // with it, and frames can be inside other frames.
+    Frame   { x: f64, y: f64, width: f64, height: f64, z: i32,
+        title: String,
+        id: Option<String>,
+        parent: Option<String>,
+        locked: bool,
+        hidden: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum StrokeStyle {
+    #[default]
+    Solid,
+    Dashed,
+    Dotted,
+}
+
+// The part of an image to show, as fractions of its full size. "crop:0.1,0,0.5,1" is the middle
+// half, left to right, of the whole height.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct Crop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+// What's drawn in a Rect's box. "shape:ellipse", "shape:rounded!radius:12", and so on.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum Shape {
+    #[default]
+    Rectangle,
+    RoundedRect { radius: f64 }, // in pixels
+    Ellipse,
+    Polygon { points: Vec<ShapePoint> }, // closed and filled
+    Polyline { points: Vec<ShapePoint> }, // open, and only drawn with the stroke (or the fill color, without one)
+}
+
+// A corner of a polygon or polyline, as fractions of its box (like Crop), so it stretches along with
+// the box. "points:0.5,0 1,1 0,1" is a triangle pointing up.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct ShapePoint {
+    pub x: f64,
+    pub y: f64,
+}
+
+// A point along a freehand stroke, relative to the top left of its Ink element. Pressure goes from
+// 0 to 1, and is 0.5 throughout for a mouse.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct InkPoint {
+    pub x: f64,
+    pub y: f64,
+    pub pressure: f64,
+}
+
+// How a text box itself looks, for sticky notes and the like without a Rect drawn behind them:
+// "background:#ffc857!border:#e0a526!padding:12!align:center". Everything's optional, and falls back
+// to the page's own styles when it isn't set.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct TextStyle {
+    pub background: Option<Color>,
+    pub border: Option<Color>, // the border's color, None for no border
+    pub border_width: Option<f64>, // in pixels, 1 if there's a border but no width
+    pub padding: Option<f64>, // in pixels, on all four sides
+    pub font: Option<String>, // a list of font families, "font:Georgia, serif"
+    pub font_size: Option<f64>, // in pixels
+    pub color: Option<Color>, // of the text
+    pub align: Option<TextAlign>,
+    pub class: Option<String>, // extra css classes for the box, space separated
+
+    #[serde(skip)]
+    pub rejected: BTreeMap<String, String>, // anything that was refused, as written, so it's saved back as it was
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+    Justify,
+}
+
+// Dungeon is the markdown described in the readme, a superset of most of CommonMark that parts
+// from it wherever I think it gets things wrong (vertical space, __underline__, and so on).
+// CommonMark sticks as close to the spec as the rest of the app allows, for notes pasted in from
+// elsewhere. Set per text box with "markdown:commonmark" in its header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum Markdown {
+    #[default]
+    Dungeon,
+    CommonMark,
 }
 
 const DEFAULT_TEXTBOX_X : f64 = -350.;
 const DEFAULT_TEXTBOX_Y : f64 = 30.;
 const DEFAULT_TEXTBOX_WIDTH : f64 = 700.;
 
+const DEFAULT_LINE_COLOR : &str = "#f3f7f0";
+const DEFAULT_LINE_WIDTH : f64 = 2.;
+
+const DEFAULT_RECT_RADIUS : f64 = 8.;
+const DEFAULT_RECT_OPACITY : f64 = 1.;
+
+const DEFAULT_INK_WIDTH : f64 = 3.;
+const DEFAULT_INK_PRESSURE : f64 = 0.5;
+
+// how far (in pixels, and in pressure) a point can be from the simplified stroke drawn on the page
+const INK_TOLERANCE : f64 = 0.5;
+const INK_PRESSURE_TOLERANCE : f64 = 0.05;
+
 impl Element {
+    // A new text box with the given contents, which is only parsed (filling in data) the next time
+    // the document is.
+    pub fn text_box(x: f64, y: f64, raw_content: String) -> Self {
+        Element::TextBox { x, y, width: DEFAULT_TEXTBOX_WIDTH, z: 0, rotation: 0.0, id: None, parent: None, locked: false, hidden: false, style: TextStyle::default(), markdown: Markdown::default(), data: vec![], raw_content }
+    }
+
+    // Elements are drawn in order of z, and then of key.
+    pub fn z(&self) -> i32 {
+        match self {
+            Element::Line { z, .. } | Element::Rect { z, .. } | Element::TextBox { z, .. } | Element::ImageBox { z, .. }
+            | Element::Ink { z, .. } | Element::Frame { z, .. } => *z,
+        }
+    }
+
+    fn z_mut(&mut self) -> &mut i32 {
+        match self {
+            Element::Line { z, .. } | Element::Rect { z, .. } | Element::TextBox { z, .. } | Element::ImageBox { z, .. }
+            | Element::Ink { z, .. } | Element::Frame { z, .. } => z,
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Element::Line { .. } | Element::Ink { .. } => None,
+            Element::Rect { id, .. } | Element::TextBox { id, .. } | Element::ImageBox { id, .. } | Element::Frame { id, .. } => id.as_deref(),
+        }
+    }
+
+    // The id of the frame this was declared to be in, with "parent:".
+    pub fn parent(&self) -> Option<&str> {
+        match self {
+            Element::Line { parent, .. } | Element::Rect { parent, .. } | Element::TextBox { parent, .. }
+            | Element::ImageBox { parent, .. } | Element::Ink { parent, .. } | Element::Frame { parent, .. } => parent.as_deref(),
+        }
+    }
+
+    pub fn locked(&self) -> bool {
+        match self {
+            Element::Line { locked, .. } | Element::Rect { locked, .. } | Element::TextBox { locked, .. }
+            | Element::ImageBox { locked, .. } | Element::Ink { locked, .. } | Element::Frame { locked, .. } => *locked,
+        }
+    }
+
+    pub fn hidden(&self) -> bool {
+        match self {
+            Element::Line { hidden, .. } | Element::Rect { hidden, .. } | Element::TextBox { hidden, .. }
+            | Element::ImageBox { hidden, .. } | Element::Ink { hidden, .. } | Element::Frame { hidden, .. } => *hidden,
+        }
+    }
+
+    fn colors_mut(&mut self) -> Vec<&mut Color> {
+        match self {
+            Element::Line { color, .. } | Element::Ink { color, .. } => vec![color],
+            Element::Rect { color, stroke, .. } => std::iter::once(color).chain(stroke.as_mut()).collect(),
+            Element::TextBox { style, .. } => [&mut style.background, &mut style.border, &mut style.color].into_iter().flatten().collect(),
+            Element::ImageBox { .. } | Element::Frame { .. } => vec![],
+        }
+    }
+
+    // Whether other is in the same place, at the same size, as this.
+    pub fn same_geometry(&self, other: &Element) -> bool {
+        self.bounds() == other.bounds() && self.extent() == other.extent()
+    }
+
+    fn translate(&mut self, dx: f64, dy: f64) {
+        match self {
+            Element::Line { x1, y1, x2, y2, .. } => { *x1 += dx; *y1 += dy; *x2 += dx; *y2 += dy; },
+            Element::Rect { x, y, .. } | Element::TextBox { x, y, .. } | Element::ImageBox { x, y, .. }
+            | Element::Ink { x, y, .. } | Element::Frame { x, y, .. } => { *x += dx; *y += dy; },
+        }
+    }
+
+    // (x, y, width, height, rotation) of anything a line can be attached to. Text boxes grow to fit
+    // whatever's in them, which isn't known until they're on the page, so their height is None.
+    fn bounds(&self) -> Option<(f64, f64, f64, Option<f64>, f64)> {
+        match self {
+            Element::Line { .. } | Element::Ink { .. } => None,
+            Element::Rect { x, y, width, height, rotation, .. } | Element::ImageBox { x, y, width, height, rotation, .. } => {
+                Some((*x, *y, *width, Some(*height), *rotation))
+            },
+            Element::TextBox { x, y, width, rotation, .. } => Some((*x, *y, *width, None, *rotation)),
+            Element::Frame { x, y, width, height, .. } => Some((*x, *y, *width, Some(*height), 0.0)),
+        }
+    }
+
+    // (left, top, right, bottom) of everything drawn for the element, including any rotation.
+    fn extent(&self) -> (f64, f64, f64, f64) {
+        let around = |points: &mut dyn Iterator<Item = (f64, f64)>| points.fold(
+            (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            |(left, top, right, bottom), (x, y)| (left.min(x), top.min(y), right.max(x), bottom.max(y)),
+        );
+        match self {
+            Element::Line { x1, y1, x2, y2, .. } => around(&mut [(*x1, *y1), (*x2, *y2)].into_iter()),
+            Element::Ink { x, y, strokes, .. } if strokes.iter().any(|stroke| !stroke.is_empty()) => {
+                around(&mut strokes.iter().flatten().map(|point| (x + point.x, y + point.y)))
+            },
+            Element::Ink { x, y, .. } => (*x, *y, *x, *y),
+            _ => {
+                // turn the corners of the box (only the top edge, for a text box) around its top left
+                let (x, y, width, height, rotation) = self.bounds().unwrap();
+                let (sin, cos) = rotation.to_radians().sin_cos();
+                let corners = [(0.0, 0.0), (width, 0.0), (0.0, height.unwrap_or(0.0)), (width, height.unwrap_or(0.0))];
+                around(&mut corners.into_iter().map(|(dx, dy)| (x + dx * cos - dy * sin, y + dx * sin + dy * cos)))
+            },
+        }
+    }
+
     pub fn write_repr(&self) -> String {
 
         // !!!!Text!x:-55.0!y:30.0!width:700.0!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
+        // (padded out to 80 characters, but always ending in at least three !s so a long header
+        // still reads back as one)
+        let header_line = |header: String| format!("{:!<80}\n", header + "!!");
+        let id_property = |id: &Option<String>| id.as_ref().map(|id| format!("id:{}!", id)).unwrap_or_default();
+        let parent_property = |parent: &Option<String>| parent.as_ref().map(|parent| format!("parent:{}!", parent)).unwrap_or_default();
+        let flags = |locked: bool, hidden: bool| (if locked { "locked:true!" } else { "" }).to_string() + if hidden { "hidden:true!" } else { "" };
+
         match self {
-            Element::Line { x1, y1, x2, y2 } =>
-                format!("{:!<80}\n", format!("!!!!Line!x1:{:.1}!y1:{:.1}!x2:{:.1}!y2:{:.1}", x1, y1, x2, y2)),
+            Element::Line { x1, y1, x2, y2, z, color, width, style, start_arrow, end_arrow, from, to, parent, locked, hidden } => {
+                // only write non-default values, past the ends themselves
+                let mut header = format!("!!!!Line!x1:{:.1}!y1:{:.1}!x2:{:.1}!y2:{:.1}!", x1, y1, x2, y2);
+                if *z != 0 { header += &format!("z:{}!", z); }
+                if color.written != DEFAULT_LINE_COLOR { header += &format!("color:{}!", color.written); }
+                if *width != DEFAULT_LINE_WIDTH { header += &format!("width:{:.1}!", width); }
+                match style {
+                    StrokeStyle::Solid => {},
+                    StrokeStyle::Dashed => header += "style:dashed!",
+                    StrokeStyle::Dotted => header += "style:dotted!",
+                }
+                match (start_arrow, end_arrow) {
+                    (false, false) => {},
+                    (true, false) => header += "arrows:start!",
+                    (false, true) => header += "arrows:end!",
+                    (true, true) => header += "arrows:both!",
+                }
+                if let Some(from) = from { header += &format!("from:{}!", from); }
+                if let Some(to) = to { header += &format!("to:{}!", to); }
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header_line(header)
+            },
 
-            Element::Rect { x, y, width, height, z, color } =>
-                format!("{:!<80}\n",
-                    format!("!!!!Rect!x:{:.1}!y:{:.1}!width:{:.1}!height:{:.1}!z:{}!color:{}",
-                            x, y, width, height, z, color)
-                    ),
+            Element::Rect { x, y, width, height, z, rotation, color, id, shape, stroke, stroke_width, style, opacity, parent, locked, hidden } => {
+                // the box itself is always written, but anything past that only when it isn't the default
+                let mut header = format!("!!!!Rect!x:{:.1}!y:{:.1}!width:{:.1}!height:{:.1}!z:{}!", x, y, width, height, z);
+                if *rotation != 0.0 { header += &format!("rotation:{:.1}!", rotation); }
+                header += &format!("color:{}!", color.written);
+                let points_property = |points: &Vec<ShapePoint>| points.iter().map(|point| format!("{},{}", point.x, point.y)).collect::<Vec<_>>().join(" ");
+                match shape {
+                    Shape::Rectangle => {},
+                    Shape::RoundedRect { radius } if *radius == DEFAULT_RECT_RADIUS => header += "shape:rounded!",
+                    Shape::RoundedRect { radius } => header += &format!("shape:rounded!radius:{:.1}!", radius),
+                    Shape::Ellipse => header += "shape:ellipse!",
+                    Shape::Polygon { points } => header += &format!("shape:polygon!points:{}!", points_property(points)),
+                    Shape::Polyline { points } => header += &format!("shape:polyline!points:{}!", points_property(points)),
+                }
+                if let Some(stroke) = stroke { header += &format!("stroke:{}!", stroke.written); }
+                if *stroke_width != DEFAULT_LINE_WIDTH { header += &format!("stroke_width:{:.1}!", stroke_width); }
+                match style {
+                    StrokeStyle::Solid => {},
+                    StrokeStyle::Dashed => header += "style:dashed!",
+                    StrokeStyle::Dotted => header += "style:dotted!",
+                }
+                if *opacity != DEFAULT_RECT_OPACITY { header += &format!("opacity:{}!", opacity); }
+                header += &id_property(id);
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header_line(header)
+            },
 
-            Element::TextBox { x, y, width, data: _, raw_content } => {
+            Element::TextBox { x, y, width, z, rotation, id, parent, locked, hidden, style, markdown, data: _, raw_content } => {
                 // only write non-default values
                 let mut header = "!!!!Text!".to_string();
                 if *x != DEFAULT_TEXTBOX_X { header += &format!("x:{:.1}!", x); }
                 if *y != DEFAULT_TEXTBOX_Y { header += &format!("y:{:.1}!", y); }
                 if *width != DEFAULT_TEXTBOX_WIDTH { header += &format!("width:{:.1}!", width); }
-                format!("{:!<80}\n{}", header, raw_content)
+                if *z != 0 { header += &format!("z:{}!", z); }
+                if *rotation != 0.0 { header += &format!("rotation:{:.1}!", rotation); }
+                header += &id_property(id);
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header += &text_style_properties(style);
+                if *markdown == Markdown::CommonMark { header += "markdown:commonmark!"; }
+                header_line(header) + raw_content
+            },
+
+            Element::ImageBox { x, y, width, height, z, rotation, id, src, url: _, crop, parent, locked, hidden } => {
+                let mut header = format!("!!!!Image!x:{:.1}!y:{:.1}!width:{:.1}!height:{:.1}!z:{}!", x, y, width, height, z);
+                if *rotation != 0.0 { header += &format!("rotation:{:.1}!", rotation); }
+                header += &format!("{}src:{}!", id_property(id), src);
+                if let Some(Crop { x, y, width, height }) = crop { header += &format!("crop:{},{},{},{}!", x, y, width, height); }
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header_line(header)
+            },
+
+            Element::Ink { x, y, z, color, width, strokes: _, recorded, raw_content, parent, locked, hidden } => {
+                let mut header = format!("!!!!Ink!x:{:.1}!y:{:.1}!z:{}!", x, y, z);
+                if color.written != DEFAULT_LINE_COLOR { header += &format!("color:{}!", color.written); }
+                if *width != DEFAULT_INK_WIDTH { header += &format!("width:{:.1}!", width); }
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header_line(header) + &write_strokes(raw_content, recorded)
+            },
+
+            Element::Frame { x, y, width, height, z, title, id, parent, locked, hidden } => {
+                let mut header = format!("!!!!Frame!x:{:.1}!y:{:.1}!width:{:.1}!height:{:.1}!z:{}!", x, y, width, height, z);
+                if !title.is_empty() { header += &format!("title:{}!", title); }
+                header += &id_property(id);
+                header += &parent_property(parent);
+                header += &flags(*locked, *hidden);
+                header_line(header)
             },
 
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+fn text_style_properties(style: &TextStyle) -> String {
+    let TextStyle { background, border, border_width, padding, font, font_size, color, align, class, rejected } = style;
+    let align = align.map(|align| match align {
+        TextAlign::Left => "left",
+        TextAlign::Center => "center",
+        TextAlign::Right => "right",
+        TextAlign::Justify => "justify",
+    });
+    let properties = [
+        ("background", background.as_ref().map(|color| color.written.clone())),
+        ("border", border.as_ref().map(|color| color.written.clone())),
+        ("border_width", border_width.map(|width| width.to_string())),
+        ("padding", padding.map(|padding| padding.to_string())),
+        ("font", font.clone()),
+        ("font_size", font_size.map(|size| size.to_string())),
+        ("color", color.as_ref().map(|color| color.written.clone())),
+        ("align", align.map(str::to_string)),
+        ("class", class.clone()),
+    ];
+    properties.into_iter()
+        .filter_map(|(name, value)| Some(format!("{}:{}!", name, value.or_else(|| rejected.get(name).cloned())?)))
+        .collect()
+}
+
+// Each stroke of an Ink element is one line of its body, so adding a stroke is a one line diff:
+//
+//     0,0,50 12,3 15,4,2 9,-1 ...
+//
+// The first point is where the stroke starts, and every one after is how far it is from the one
+// before. x and y are in tenths of a pixel, and pressure in percent, left out while it's unchanged.
+fn encode_stroke(points: &[InkPoint]) -> String {
+    let mut encoded = Vec::with_capacity(points.len());
+    let (mut x0, mut y0, mut p0) = (0, 0, None);
+
+    for point in points {
+        // round each point before taking the differences, so the errors don't add up along the stroke
+        let x = (point.x * 10.0).round() as i64;
+        let y = (point.y * 10.0).round() as i64;
+        let p = (point.pressure * 100.0).round() as i64;
+        encoded.push(match p0 {
+            Some(p0) if p0 == p => format!("{},{}", x - x0, y - y0),
+            _ => format!("{},{},{}", x - x0, y - y0, p - p0.unwrap_or(0)),
+        });
+        (x0, y0, p0) = (x, y, Some(p));
+    }
+    encoded.join(" ")
+}
+
+// The body of an Ink element: each stroke that's still the same as one in raw (the body it was read
+// from) just as it was written there, in the same place, along with any lines that weren't strokes,
+// and anything else newly encoded at the end.
+fn write_strokes(raw: &str, recorded: &[Vec<InkPoint>]) -> String {
+    let mut strokes = recorded.iter().peekable();
+    let mut body = String::new();
+    for line in raw.lines() {
+        match decode_stroke(line) {
+            Some(stroke) if !line.trim().is_empty() => if strokes.peek() == Some(&&stroke) {
+                strokes.next();
+                body += &format!("{}\n", line);
+            },
+            _ => body += &format!("{}\n", line),
+        }
+    }
+    body + &strokes.map(|stroke| encode_stroke(stroke) + "\n").collect::<String>()
+}
+
+fn decode_stroke(line: &str) -> Option<Vec<InkPoint>> {
+    let (mut x, mut y, mut p) = (0, 0, None);
+
+    line.split_whitespace().map(|point| {
+        let numbers = point.split(',').map(|number| number.parse::<i64>()).collect::<Result<Vec<i64>, _>>().ok()?;
+        let (dx, dy, dp) = match numbers[..] {
+            [dx, dy] => (dx, dy, None),
+            [dx, dy, dp] => (dx, dy, Some(dp)),
+            _ => return None,
+        };
+        x += dx;
+        y += dy;
+        p = match (p, dp) {
+            (Some(p), Some(dp)) => Some(p + dp),
+            (None, Some(dp)) => Some(dp),
+            (p, None) => p,
+        };
+        Some(InkPoint {
+            x: x as f64 / 10.0,
+            y: y as f64 / 10.0,
+            pressure: p.map_or(DEFAULT_INK_PRESSURE, |p| (p as f64 / 100.0).clamp(0.0, 1.0)),
+        })
+    }).collect()
+}
+
+// Drop every point that's close enough to the line between its neighbours to not be missed
+// (Ramer-Douglas-Peucker, with pressure as a third dimension), which for most strokes is nearly all
+// of them.
+fn simplify_stroke(points: &[InkPoint]) -> Vec<InkPoint> {
+    if points.len() < 3 { return points.to_vec(); }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let (a, b) = (points[start], points[end]);
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let length = dx.hypot(dy);
+
+        // how far each point between them is from where it'd be on the straight line from a to b,
+        // relative to the tolerances
+        let furthest = (start + 1..end).map(|i| {
+            let point = points[i];
+            let t = if length > 0.0 { (((point.x - a.x) * dx + (point.y - a.y) * dy) / (length * length)).clamp(0.0, 1.0) } else { 0.0 };
+            let distance = (point.x - a.x - t * dx).hypot(point.y - a.y - t * dy);
+            let pressure = (point.pressure - a.pressure - t * (b.pressure - a.pressure)).abs();
+            (i, f64::max(distance / INK_TOLERANCE, pressure / INK_PRESSURE_TOLERANCE))
+        }).max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        if let Some((i, deviation)) = furthest && deviation > 1.0 {
+            keep[i] = true;
+            ranges.push((start, i));
+            ranges.push((i, end));
+        }
+    }
+
+    points.iter().zip(keep).filter(|(_, keep)| *keep).map(|(point, _)| *point).collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
 pub enum TextBlock {
     Paragraph { chunks: Vec<TextChunk> },
     Header { level: u8, chunks: Vec<TextChunk> },
     CodeBlock { text: String },
     MathBlock { text: String },
-    UnorderedList { items: Vec<TextBlock> },
-    OrderedList { items: Vec<TextBlock> },
+    UnorderedList { items: Vec<Vec<TextBlock>> }, // each item is its own list of blocks
+    OrderedList { start: u32, style: ListStyle, delimiter: ListDelimiter, items: Vec<Vec<TextBlock>> },
     BlockQuote { inner: Vec<TextBlock> },
-    Image { url: String, alt: String },
+    Callout {
+        kind: String, // "note", "warning", ..., always lowercase
+        title: Vec<TextChunk>, // empty if none was given
+        folded: Option<bool>, // None if it can't be folded away, otherwise whether it starts out folded
+        inner: Vec<TextBlock>,
+    },
+    DefinitionList { items: Vec<DefinitionItem> },
+    Image {
+        url: String,
+        alt: String,
+        title: Option<String>,  // shown on hover
+        caption: Vec<TextChunk>, // shown under the image, empty for none
+        width: Option<u32>,     // in pixels. Whatever isn't given in the attributes is filled in from
+        height: Option<u32>,    // the file itself, if it's local and we can read its header.
+        thumbnail: Option<String>, // a smaller copy to show instead, for big local images
+    },
     VerticalSpace,
     HorizontalRule,
 }
 
-#[derive(Debug, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+// 1. 2. 3., i. ii. iii., a. b. c., and so on. Whatever the first item of a list uses.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum ListStyle { Decimal, LowerRoman, UpperRoman, LowerAlpha, UpperAlpha }
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub enum ListDelimiter { Period, Paren }
+
+// One or more terms, one per line, followed by each of their definitions
+//
+//     Term
+//     : definition
+//     : another definition
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
+pub struct DefinitionItem {
+    pub terms: Vec<Vec<TextChunk>>,
+    pub definitions: Vec<Vec<TextBlock>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Elm, ElmEncode, ElmDecode)]
 pub enum TextChunk {
     Link { title: Vec<TextChunk>, url: String },
     Code { text: String },
@@ -134,28 +672,28 @@ struct ElementPrecursor {
 #[derive(Debug)]
 enum TextBlockPrecursor<'a> {
 
-    Header { level: u8, text: &'a str },
+    Header { level: u8, lines: Vec<&'a str> }, // only a setext header has more than one line
 
-    CodeBlock { lang: Option<&'a str>, text: &'a str },
-    MathBlock { text: &'a str },
-    UnorderedList { items: Vec<TextBlockPrecursor<'a>> },
-    OrderedList { items: Vec<TextBlockPrecursor<'a>> },
+    CodeBlock { lang: Option<&'a str>, lines: Vec<&'a str> },
+    MathBlock { lines: Vec<&'a str> },
+    UnorderedList { items: Vec<Vec<TextBlockPrecursor<'a>>> },
+    OrderedList { start: usize, style: ListStyle, delimiter: ListDelimiter, items: Vec<Vec<TextBlockPrecursor<'a>>> },
 
-    BlockQuote { inner: Vec<TextBlock> },
+    BlockQuote { inner: Vec<TextBlockPrecursor<'a>> },
 
-    Image { url: String, alt: String },
+    // "> [!TIP]- title", the github / obsidian syntax
+    Callout { kind: &'a str, title: &'a str, folded: Option<bool>, inner: Vec<TextBlockPrecursor<'a>> },
 
-    // With the List type elements, it's possible to parse their contents purely on the original
-    // text buffer - hence their array is also of Precursors. Less so for blockquotes - I need to
-    // create a custom de-indented buffer for the recursive parser call to run on, so I parse their
-    // contents all the way to a TextBlock from the outset as to not have a return value that's
-    // dependent on data that will go out of scope.
-    //
-    // Note: the "right" solution here would probably look a lot more like writing my own take on
-    // &str slices, so I can iterate over the text in weird ways (like skipping " > " at the start
-    // of each block comment line) without ever needing to copy the underlying data. However that
-    // would mean I probably lose out on built in Regex, and the like, so I'll take the tradeoff
-    // for now.
+    DefinitionList { items: Vec<(Vec<&'a str>, Vec<Vec<TextBlockPrecursor<'a>>>)> }, // (terms, definitions)
+
+    // ![alt](url "title"){width=300 caption}
+    Image { url: &'a str, alt: &'a str, title: Option<&'a str>, attributes: Option<&'a str> },
+
+    // Block parsing runs on lines, where every line is a slice of the one immutable buffer holding
+    // the element's text. Nested structures are handled by handing the recursive call the same
+    // lines with their markers sliced off the front (skipping "> " at the start of each blockquote
+    // line, say), so nothing gets copied until conversion. Anything that can cover more than one
+    // line just holds onto the list of slices, to be joined back up when it's converted.
 
     // Man I miss Haskell. Lazy evaluation would be so *cool* here - even if it's not actually
     // better.
@@ -192,9 +730,19 @@ enum TextBlockPrecursor<'a> {
 
     HorizontalRule,
 
-    Paragraph { text: String }, // this one is String instead of &str for concatenation.
-                                // Fix later, so we're just referencing indices into the original
-                                // string without copying.
+    Paragraph { lines: Vec<&'a str> }, // joined with spaces when converted (newlines for commonmark)
+}
+
+// The start of a list item, "- " or "12. " or similar.
+#[derive(Debug)]
+struct ListMarker {
+    ordered: bool,
+    delimiter: char,       // the bullet, or the . or ) after a number
+    number: Option<usize>, // None for bullets
+    style: ListStyle,
+    length: usize,         // bytes from the start of the line to the start of the item's content
+    indent: usize,         // column the content starts at, which any following lines have to match
+    empty: bool,           // nothing after the marker
 }
 
 // -----------------------------------------------------------------------------
@@ -205,43 +753,91 @@ lazy_static! {
 
     // a element header will look like this:
     // !!!!Text!x:370.0!y:150.0!width:300.0!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-    static ref ELEMENT_HEADER_REGEX: Regex = Regex::new(r"(?i)^!!!+(text|line|rect)(?:!+\w+:[^!]+)*!!!+[ \t]*$").unwrap();
+    static ref ELEMENT_HEADER_REGEX: Regex = Regex::new(r"(?i)^!!!+(text|line|rect|image|ink|frame|palette)(?:!+\w+:[^!]+)*!!!+[ \t]*$").unwrap();
     static ref ELEMENT_PROPERTY_REGEX: Regex = Regex::new(r"!+(\w+):([^!]+)").unwrap();
 
-    // matches numbers, but also some simple roman numerals. The choice of
-    // which you use doesn't actually effect the output (yet, look into this)
-    static ref ORDERED_LIST_REGEX: Regex = Regex::new(r"^(?:[ \t]*)((?:[ivx]+|\d+)\.[ \t]+)").unwrap();
+    // matches numbers, single letters, and roman numerals, followed by a . or ). Which one is used
+    // (and what it starts at) is kept, so "iv." starts a list at 4 in roman numerals.
+    static ref ORDERED_LIST_REGEX: Regex = Regex::new(r"^(?:[ \t]*)((\d{1,9}|[a-zA-Z]|[ivxlcdm]+|[IVXLCDM]+)([.)])[ \t]+)").unwrap();
+
+    static ref ROMAN_NUMERAL_REGEX: Regex = Regex::new(r"^m{0,3}(cm|cd|d?c{0,3})(xc|xl|l?x{0,3})(ix|iv|v?i{0,3})$").unwrap();
 
     static ref UNORDERED_LIST_REGEX: Regex = Regex::new(r"^(?:[ \t]*)([*+-][ \t]+)").unwrap();
 
-    static ref BLOCKQUOTE_REGEX: Regex = Regex::new(r"^(?:[ \t]*)>(.*)").unwrap();
+    // the first line of a callout, "[!WARNING]", "[!tip] A title", or "[!note]- folded"
+    static ref CALLOUT_REGEX: Regex = Regex::new(r"^[ \t]*\[!([\w-]+)\]([+-]?)(?:[ \t]+(.*?))?[ \t]*$").unwrap();
+
+    // "https://example.com/foo" or "www.example.com", as found in text. Trailing punctuation is
+    // trimmed off afterwards.
+    static ref URL_REGEX: Regex = Regex::new(r"^(?:https?://|ftp://|www\.)[\w-]+(?:\.[\w-]+)*[^\s<]*").unwrap();
+    static ref EMAIL_REGEX: Regex = Regex::new(r"^[\w.+-]+@[\w-]+(?:\.[\w-]+)+").unwrap();
 
-    static ref IMAGE_REGEX: Regex = Regex::new(r"^(?:[ \t]*)!\[(.*)\]\((.*)\)").unwrap();
+    // "<https://example.com>", "<foo@example.com>", and anything else with a scheme
+    static ref ANGLE_AUTOLINK_REGEX: Regex = Regex::new(r"^<([a-zA-Z][a-zA-Z0-9+.-]{1,31}:[^<>\x00-\x20]*|[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*)>").unwrap();
 
-    // For italics, I have a regex that will match a single asterisk, only if
-    // there's not a second asterisk right after it.
-    static ref ITALIC_REGEX: Regex = Regex::new(r"(?:^|[^\*])(\*)(?:[^\*]|$)").unwrap();
+    // ![alt](url "title"){attributes}, where the url can have spaces or (one level of) brackets
+    static ref IMAGE_REGEX: Regex = Regex::new(r#"^[ \t]*!\[(.*?)\]\([ \t]*((?:[^()]|\([^()]*\))*?)(?:[ \t]+(?:"(.*?)"|'(.*?)'))?[ \t]*\)(?:\{([^}]*)\})?"#).unwrap();
 
+    // width=300 height="200px" caption
+    static ref IMAGE_ATTRIBUTE_REGEX: Regex = Regex::new(r#"([\w-]+)(?:=(?:"([^"]*)"|([^\s"]+)))?"#).unwrap();
+
+}
+
+// Spinning up a prism context means booting a fresh QuickJS runtime and evaluating all of
+// prism.js, which takes around two seconds in a release build. Doing that once per code block was
+// the thing making larger notes take ages to reload. QuickJS contexts can't be sent between
+// threads, so each thread gets its own, created the first time it actually needs to highlight.
+thread_local! {
+    static PRISM_CONTEXT: RefCell<Option<PrismContext>> = RefCell::new(None);
+}
+
+fn highlight_code(lang: &str, code: &str) -> String {
+    cache::get_or_render("prism", lang, code, || {
+        PRISM_CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            highlight(context.get_or_insert_with(init), code, lang)
+        }).unwrap_or_else(|| escape_html(code)) // no such language
+    })
+}
+
+fn render_math(math: &str, display_mode: bool) -> String {
+    let options = if display_mode { "display" } else { "inline" };
+    cache::get_or_render("katex", options, math, || {
+        let opts = katex::Opts::builder()
+                    .output_type(katex::OutputType::HtmlAndMathml)
+                    .display_mode(display_mode)
+                    .throw_on_error(false)
+                    .build().unwrap();
+        katex::render_with_opts(math, opts).unwrap()
+    })
 }
 
 // -----------------------------------------------------------------------------
 
 fn parse_float(precursor: &ElementPrecursor, name: &str, default: Option<f64>) -> f64 {
     if let Some(value) = precursor.properties.get(name) {
-        value.parse().expect(format!("invalid {} value: {}", name, value).as_str())
+        value.parse().unwrap_or_else(|_| panic!("invalid {} value: {}", name, value))
     } else { match default {
         Some(value) => value,
         None => panic!("unable to find {} value", name),
     }}
 }
 
-fn parse_int(precursor: &ElementPrecursor, name: &str, default: Option<i32>) -> i32 {
-    if let Some(value) = precursor.properties.get(name) {
-        value.parse().expect(format!("invalid {} value: {}", name, value).as_str())
-    } else { match default {
-        Some(value) => value,
-        None => panic!("unable to find {} value", name),
-    }}
+// For properties that can be left out: anything that isn't a number is a diagnostic, and the default.
+fn parse_float_or(precursor: &ElementPrecursor, name: &str, default: f64) -> f64 {
+    match precursor.properties.get(name).map(|value| (value, value.trim().parse::<f64>())) {
+        None => default,
+        Some((_, Ok(value))) if value.is_finite() => value,
+        Some((value, _)) => { diagnose(format!("invalid {} value: {}", name, value)); default },
+    }
+}
+
+fn parse_int_or(precursor: &ElementPrecursor, name: &str, default: i32) -> i32 {
+    match precursor.properties.get(name).map(|value| (value, value.trim().parse::<i32>())) {
+        None => default,
+        Some((_, Ok(value))) => value,
+        Some((value, _)) => { diagnose(format!("invalid {} value: {}", name, value)); default },
+    }
 }
 
 fn parse_string(precursor: &ElementPrecursor, name: &str, default: Option<String>) -> String {
@@ -253,6 +849,76 @@ fn parse_string(precursor: &ElementPrecursor, name: &str, default: Option<String
     }}
 }
 
+// "locked:true" and so on. Anything left out (or not true or false) is false.
+fn parse_bool(precursor: &ElementPrecursor, name: &str) -> bool {
+    match precursor.properties.get(name).map(|value| value.to_lowercase()).as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(value) => { diagnose(format!("invalid {} value: {}", name, value)); false },
+    }
+}
+
+fn parse_markdown(precursor: &ElementPrecursor) -> Markdown {
+    match precursor.properties.get("markdown").map(|value| value.to_lowercase()).as_deref() {
+        None | Some("dungeon") => Markdown::Dungeon,
+        Some("commonmark") => Markdown::CommonMark,
+        Some(value) => { diagnose(format!("invalid markdown value: {}", value)); Markdown::Dungeon },
+    }
+}
+
+// Settings for a whole run of the server, set once from the command line before the first parse.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub root: PathBuf, // the document's directory, which local links and images are relative to
+    pub flag_unsafe_urls: bool, // report links and images with a disallowed url, rather than quietly dropping them
+}
+
+// Elements are parsed independently of each other, so anything with the same type, header
+// properties and body as it had last parse can be reused as is (diagnostics and all), as long as
+// the local images it read the size of haven't changed since. Only the elements used by the most
+// recent parse are kept around, so this never grows past the size of one document.
+lazy_static! {
+    static ref OPTIONS: RwLock<Options> = RwLock::new(Options::default());
+    static ref ELEMENT_CACHE: Mutex<HashMap<u64, CachedElement>> = Mutex::new(HashMap::new());
+}
+
+// (the element, its diagnostics, and the files it read)
+type CachedElement = (Element, Vec<String>, Vec<Dependency>);
+
+// a file, with when it was last modified and its size (if it's there at all)
+type Dependency = (PathBuf, Option<(SystemTime, u64)>);
+
+pub fn configure(options: Options) {
+    *OPTIONS.write().unwrap() = options;
+}
+
+// Diagnostics raised while parsing an element, collected on whichever thread is parsing it rather
+// than passed through every function between parse_element and the url of some link in a list.
+thread_local! {
+    static DIAGNOSTICS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+fn diagnose(message: String) {
+    DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().push(message));
+}
+
+// Likewise for the files read while parsing an element, which it's only cached for as long as
+// they're unchanged.
+thread_local! {
+    static DEPENDENCIES: RefCell<Vec<Dependency>> = RefCell::new(Vec::new());
+}
+
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// image::dimensions, for anything the parsed element depends on.
+fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    DEPENDENCIES.with(|dependencies| dependencies.borrow_mut().push((path.to_path_buf(), file_stamp(path))));
+    image::dimensions(path)
+}
+
 pub fn parse(text: &str) -> Document {
     let mut document = Document::new();
 
@@ -272,29 +938,44 @@ pub fn parse(text: &str) -> Document {
         });
     }
 
-    for (i, line) in text.lines().enumerate() {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut palette_bodies = Vec::new();
+    let mut palette_line = None;
+
+    for (i, line) in lines.iter().enumerate() {
         if let Some(caps) = ELEMENT_HEADER_REGEX.captures(line) {
 
             // first, close off the previous element.
             let l = element_precursors.len();
             if l > 0 { element_precursors[l - 1].endline = i - 1; }
 
-            // type_ will be one of "text", "line", or "rect"
+            // type_ will be one of "text", "line", "rect", "image", "ink", "frame" or "palette"
             let type_ = caps.get(1).unwrap().as_str().to_string().to_lowercase();
 
             // properties will be a Map of key/value pairs, things like "width" : "750.0"
-            let mut properties = BTreeMap::new();
-
-            let mut line = caps.get(0).unwrap().as_str();
-
-            while let Some(caps) = ELEMENT_PROPERTY_REGEX.captures(line) {
-                let key = caps.get(1).unwrap().as_str().to_string().to_lowercase();
-                let value = caps.get(2).unwrap().as_str().to_string();
-                properties.insert(key, value);
-                line = &line[caps.get(0).unwrap().end()..];
+            let properties = header_properties(caps.get(0).unwrap().as_str());
+
+
+            // A palette isn't an element itself, just colors for the rest of the document. Anything
+            // written under one goes in a text box, the same as at the very top of the document.
+            if type_ == "palette" {
+                palette_line.get_or_insert(i);
+                for (name, value) in properties {
+                    // (which can't be named after each other, only after css's own colors)
+                    let mut color = Color::parse(&value).unwrap_or_else(|| Color { written: value.clone(), css: String::new() });
+                    if !color.resolve(&BTreeMap::new()) {
+                        document.diagnostics.push(Diagnostic {
+                            element: "palette".to_string(),
+                            message: format!("invalid color for {} in the palette: {}", name, value),
+                        });
+                    }
+                    document.palette.insert(name, color);
+                }
+                element_precursors.push(ElementPrecursor { type_: "text".to_string(), properties: BTreeMap::new(), startline: i + 1, endline: 0 });
+                palette_bodies.push(element_precursors.len() - 1);
+                continue;
             }
 
-
             let precursor = ElementPrecursor {
                 type_,
                 properties,
@@ -308,20 +989,29 @@ pub fn parse(text: &str) -> Document {
     }
 
     let l = element_precursors.len();
-    element_precursors[l - 1].endline = text.lines().count();
+    element_precursors[l - 1].endline = lines.len();
 
+    // (though when there isn't anything, there's no text box either)
+    let mut i = 0;
+    element_precursors.retain(|precursor| {
+        i += 1;
+        !palette_bodies.contains(&(i - 1))
+            || lines.iter().skip(precursor.startline).take(1 + precursor.endline - precursor.startline).any(|line| !line.trim().is_empty())
+    });
 
 
 
-    // now we have all the elements, we can parse the contents of each and add them to the document
 
+    // now we have all the elements, we can parse the contents of each and add them to the document,
+    // reusing anything that hasn't changed since the last parse.
 
-    for (i, precursor) in element_precursors.iter().enumerate() {
+    let elements = element_precursors.iter().enumerate().map(|(i, precursor)| {
 
-        let text = text.lines()
+        let text = lines.iter()
             .skip(precursor.startline)
             .take(1 + precursor.endline - precursor.startline)
-            .chain(std::iter::once(""))
+            .chain(std::iter::once(&""))
+            .copied()
             .collect::<Vec<&str>>()
             .join("\n");
 
@@ -331,552 +1021,2670 @@ pub fn parse(text: &str) -> Document {
         let hash = hasher.finish();
         let key = format!("{}_{:x}", i, hash);
 
+        // and the hash of everything that goes into the element (but not its position in the
+        // file) to find it in the cache
+        let mut hasher = DefaultHasher::new();
+        (&precursor.type_, &precursor.properties, &text).hash(&mut hasher);
+        let content_hash = hasher.finish();
+
+        (key, content_hash, precursor, text)
+    }).collect::<Vec<_>>();
+
+    // so it can be saved back in the same place
+    document.palette_after = palette_line.and_then(|line| {
+        elements.iter().filter(|(_, _, precursor, _)| precursor.startline <= line).last().map(|(key, ..)| key.clone())
+    });
+
+    let mut previous = std::mem::take(&mut *ELEMENT_CACHE.lock().unwrap());
+    previous.retain(|_, (_, _, dependencies)| dependencies.iter().all(|(path, stamp)| file_stamp(path) == *stamp));
+
+    // Elements don't depend on each other, so whatever isn't cached gets parsed across rayon's
+    // thread pool. Katex and prism are by far the slowest part of this, and their per-thread state
+    // lives as long as the pool's threads do.
+    let parsed = elements.par_iter()
+        .filter(|(_, content_hash, _, _)| !previous.contains_key(content_hash))
+        .map(|(_, content_hash, precursor, text)| {
+            let element = parse_element(precursor, text);
+            let diagnostics = DIAGNOSTICS.with(|diagnostics| std::mem::take(&mut *diagnostics.borrow_mut()));
+            let dependencies = DEPENDENCIES.with(|dependencies| std::mem::take(&mut *dependencies.borrow_mut()));
+            (*content_hash, (element, diagnostics, dependencies))
+        })
+        .collect::<HashMap<u64, CachedElement>>();
+
+    previous.extend(parsed);
+
+    let mut next = HashMap::new();
+
+    for (key, content_hash, _, _) in elements {
+        let (element, diagnostics, dependencies) = previous.remove(&content_hash)
+            .or_else(|| next.get(&content_hash).cloned())
+            .unwrap();
+
+        next.insert(content_hash, (element.clone(), diagnostics.clone(), dependencies));
+        document.diagnostics.extend(diagnostics.into_iter().map(|message| Diagnostic { element: key.clone(), message }));
+        document.elements.insert(key, element);
+    }
 
-        // depending on the type of element, we'll parse it differently
-        let element = match precursor.type_.as_str() {
-            "text" => Element::TextBox {
-                x: parse_float(precursor, "x", Some(DEFAULT_TEXTBOX_X)),
-                y: parse_float(precursor, "y", Some(DEFAULT_TEXTBOX_Y)),
-                width: parse_float(precursor, "width", Some(DEFAULT_TEXTBOX_WIDTH)),
-                data: parse_text_blocks(&text),
-                raw_content: text.clone(),
-            },
+    *ELEMENT_CACHE.lock().unwrap() = next;
 
-            "line" => Element::Line {
-                x1: parse_float(precursor, "x1", None),
-                y1: parse_float(precursor, "y1", None),
-                x2: parse_float(precursor, "x2", None),
-                y2: parse_float(precursor, "y2", None),
-            },
+    // Colors given by name are only looked up now, so the cached elements don't go stale when the
+    // palette changes.
+    for (key, element) in document.elements.iter_mut() {
+        for color in element.colors_mut() {
+            if !color.resolve(&document.palette) {
+                document.diagnostics.push(Diagnostic {
+                    element: key.clone(),
+                    message: format!("there's no color called {}, in the palette or otherwise", color.written.trim()),
+                });
+            }
+        }
+    }
 
-            "rect" | "rectangle" => Element::Rect {
-                x: parse_float(precursor, "x", Some(-400.0)),
-                y: parse_float(precursor, "y", Some(0.0)),
-                width: parse_float(precursor, "width", Some(800.0)),
-                height: parse_float(precursor, "height", Some(600.0)),
-                z: parse_int(precursor, "z", Some(-1)),
-                color: parse_string(precursor, "color", Some("#00827c".to_string())),
+    // Elements in a frame are written relative to it, but everything past here deals in positions on
+    // the page. The offsets are all worked out before any frame is moved.
+    let frames = frames(&document.elements);
+    let offsets = document.elements.iter().filter_map(|(key, element)| {
+        let parent = element.parent()?;
+        match ancestry(&frames, parent) {
+            Some(ancestry) => Some((key.clone(), ancestry.iter().map(|id| frames[id].0).sum(), ancestry.iter().map(|id| frames[id].1).sum())),
+            None => {
+                document.diagnostics.push(Diagnostic {
+                    element: key.clone(),
+                    message: format!("can't find a frame with id {} (or it's inside itself)", parent),
+                });
+                None
             },
+        }
+    }).collect::<Vec<(String, f64, f64)>>();
 
-            _ => panic!("unknown element type: {}", precursor.type_),
-        };
-
-        document.elements.insert(key, element);
+    for (key, dx, dy) in offsets {
+        document.elements.get_mut(&key).unwrap().translate(dx, dy);
     }
 
     document
 }
 
-// split("foobazbar", "baz") -> Some(("foo", "bar"))
-fn split<'a>(text: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
-    let mut split = text.splitn(2, delimiter);
-    let first = split.next()?;
-    let second = split.next()?;
-    Some((first, second))
+#[test]
+fn element_cache_test() {
+    // a png header that's just big enough to read the size out of (with anything after it to make
+    // it a different size on disk)
+    let png = |width: u32, height: u32, padding: usize| {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        bytes.extend(width.to_be_bytes());
+        bytes.extend(height.to_be_bytes());
+        bytes.extend(vec![0; 6 + padding]);
+        bytes
+    };
+    // in a document of its own, so the text (and so what's cached) isn't the same as any other test's
+    let name = format!("dungeon-cache-test-{}.png", std::process::id());
+    let path = std::env::temp_dir().join(&name);
+    let options = OPTIONS.read().unwrap().clone();
+    configure(Options { root: std::env::temp_dir(), ..options.clone() });
+    std::fs::write(&path, png(2000, 1000, 0)).unwrap();
+
+    let text = [
+        padded_header("!!!!Text!x:0.0!y:0.0!width:400.0!padding:-1!") + &format!("![a picture](/{})\n", name),
+        padded_header(&format!("!!!!Image!x:0.0!y:0.0!width:300.0!src:/{}!", name)),
+    ].concat();
+    let summary = |document: &Document| serde_json::to_string(&(&document.elements, &document.diagnostics)).unwrap();
+
+    // whatever's taken from the cache is the same as parsing it from scratch
+    let cold = summary(&parse(&text));
+    assert!(cold.contains(&format!("/thumbnail/800/{}", name)));
+    assert!(cold.contains("\"height\":150.0"));
+    assert!(cold.contains("invalid padding value: -1"));
+    assert_eq!(summary(&parse(&text)), cold);
+
+    // until the image is changed on disk
+    std::fs::write(&path, png(600, 600, 100)).unwrap();
+    let changed = summary(&parse(&text));
+    assert!(!changed.contains("/thumbnail/"));
+    assert!(changed.contains("\"height\":300.0"));
+
+    configure(options);
+    std::fs::remove_file(&path).unwrap();
 }
 
-// split_or_end("foobazbar", "quux") -> ("foobazbar", "")
-fn split_or_end<'a>(text: &'a str, delimiter: &str) -> (&'a str, &'a str) {
-    split(text, delimiter).unwrap_or((text, ""))
+fn header_properties(mut line: &str) -> BTreeMap<String, String> {
+    let mut properties = BTreeMap::new();
+    while let Some(caps) = ELEMENT_PROPERTY_REGEX.captures(line) {
+        let key = caps.get(1).unwrap().as_str().to_string().to_lowercase();
+        let value = caps.get(2).unwrap().as_str().to_string();
+        properties.insert(key, value);
+        line = &line[caps.get(0).unwrap().end()..];
+    }
+    properties
 }
 
-
-
-
-// I tried to turn this into a trait, but stuff is weird with lifetimes.
-fn trim_start_no_newline(text: &str) -> &str {
-    text.trim_start_matches(|c: char| c.is_whitespace() && c != '\n')
+// An element sent from the page, checked by writing it out and parsing it back in the same way as
+// the file. Anything that doesn't come back the same is refused - a title with a "!" in it that would
+// be read as another property, say, or a newline in a class that would start the body early - and
+// everything else (colors, styles, the text box's rendered text, an image's url) is taken from what
+// was parsed, rather than from the page. Returns the element with its diagnostics.
+pub fn check_update(element: &Element, palette: &BTreeMap<String, Color>) -> Result<(Element, Vec<String>), String> {
+    let written = element.write_repr();
+    let (header, body) = written.split_once('\n').unwrap_or((&written, ""));
+
+    let Some(caps) = ELEMENT_HEADER_REGEX.captures(header) else { return Err(format!("not a header: {}", header)); };
+    if let Some(line) = body.lines().find(|line| ELEMENT_HEADER_REGEX.is_match(line)) {
+        return Err(format!("a header in the body: {}", line));
+    }
+    let precursor = ElementPrecursor {
+        type_: caps.get(1).unwrap().as_str().to_lowercase(),
+        properties: header_properties(header),
+        startline: 0,
+        endline: 0,
+    };
+
+    let mut parsed = parse_element(&precursor, body);
+    let mut diagnostics = DIAGNOSTICS.with(|diagnostics| std::mem::take(&mut *diagnostics.borrow_mut()));
+    DEPENDENCIES.with(|dependencies| dependencies.borrow_mut().clear());
+
+    if parsed.write_repr() != written { return Err(format!("not saved as it was sent: {}", header)); }
+
+    for color in parsed.colors_mut() {
+        if !color.resolve(palette) {
+            diagnostics.push(format!("there's no color called {}, in the palette or otherwise", color.written.trim()));
+        }
+    }
+    Ok((parsed, diagnostics))
 }
 
-fn trim_one_newline(text: &str) -> &str {
-    if text.len() > 0 && text.starts_with('\n') {
-        &text[1..]
-    } else {
-        text
+// The whole document as it's saved, with anything in a frame written relative to it again.
+pub fn write(document: &Document) -> String {
+    let palette = match document.palette.is_empty() {
+        true => String::new(),
+        false => format!("{:!<80}\n", document.palette.iter().fold("!!!!Palette!".to_string(), |header, (name, color)| {
+            header + &format!("{}:{}!", name, color.written)
+        }) + "!!"),
+    };
+
+    // the palette goes back where it was, or at the top if what it came after is gone
+    let after = document.palette_after.as_ref().filter(|key| document.elements.contains_key(*key));
+    let mut written = if after.is_none() { palette.clone() } else { String::new() };
+    for (key, element) in written_elements(&document.elements) {
+        written += &element;
+        if after == Some(key) { written += &palette; }
     }
+    written
 }
 
-// Tabs counted as 4 spaces.
-fn count_indent(text: &str) -> usize {
-    let mut count = 0;
-    for c in text.chars() {
-        match c {
-            '\t' => count += 4,
-            ' ' => count += 1,
-            _ => break,
-        }
-    }
-    count
+// (just the elements, for the tests)
+#[cfg(test)]
+fn write_elements(elements: &BTreeMap<String, Element>) -> String {
+    written_elements(elements).map(|(_, element)| element).collect()
 }
 
-#[test]
-fn split_scope_test() {
-    let text = r#"header
-        line 1
-            line 2
-        line 3
-    line 4"#;
+fn written_elements(elements: &BTreeMap<String, Element>) -> impl Iterator<Item = (&String, String)> {
+    let frames = frames(elements);
+    elements.iter().map(move |(key, element)| {
+        let written = match element.parent().and_then(|parent| ancestry(&frames, parent)) {
+            Some(ancestry) => {
+                let (x, y, _) = frames[ancestry[0]];
+                let mut relative = element.clone();
+                relative.translate(-x, -y);
+                relative.write_repr()
+            },
+            None => element.write_repr(),
+        };
+        (key, written)
+    })
+}
 
-    let (first, second) = split_scope(text, 8, false);
+// (x, y, parent) of every frame with an id
+fn frames(elements: &BTreeMap<String, Element>) -> HashMap<&str, (f64, f64, Option<&str>)> {
+    elements.values().filter_map(|element| match element {
+        Element::Frame { x, y, id: Some(id), parent, .. } => Some((id.as_str(), (*x, *y, parent.as_deref()))),
+        _ => None,
+    }).collect()
+}
 
-    assert_eq!(first, "header\n        line 1\n            line 2\n        line 3\n");
-    assert_eq!(second, "    line 4");
+// The ids of the frame called parent, its parent, and so on up to the top of the page. None if one
+// of them doesn't exist, or they go round in a loop.
+fn ancestry<'a>(frames: &HashMap<&'a str, (f64, f64, Option<&'a str>)>, parent: &'a str) -> Option<Vec<&'a str>> {
+    let mut ancestry = vec![];
+    let mut next = Some(parent);
+    while let Some(id) = next {
+        if ancestry.contains(&id) { return None; }
+        let (_, _, parent) = frames.get(id)?;
+        ancestry.push(id);
+        next = *parent;
+    }
+    Some(ancestry)
 }
 
+fn parse_element(precursor: &ElementPrecursor, text: &str) -> Element {
+    // depending on the type of element, we'll parse it differently
+    match precursor.type_.as_str() {
+        "text" => {
+            let markdown = parse_markdown(precursor);
+            let width = parse_float(precursor, "width", Some(DEFAULT_TEXTBOX_WIDTH));
+            let mut data = parse_text_blocks(text, markdown);
+            add_thumbnails(&mut data, width);
+            Element::TextBox {
+                x: parse_float(precursor, "x", Some(DEFAULT_TEXTBOX_X)),
+                y: parse_float(precursor, "y", Some(DEFAULT_TEXTBOX_Y)),
+                width,
+                z: parse_int_or(precursor, "z", 0),
+                rotation: parse_float_or(precursor, "rotation", 0.0),
+                id: precursor.properties.get("id").cloned(),
+                parent: precursor.properties.get("parent").cloned(),
+                locked: parse_bool(precursor, "locked"),
+                hidden: parse_bool(precursor, "hidden"),
+                style: parse_text_style(precursor),
+                markdown,
+                data,
+                raw_content: text.to_string(),
+            }
+        },
+
+        "line" => {
+            let (start_arrow, end_arrow) = match precursor.properties.get("arrows").map(|value| value.to_lowercase()).as_deref() {
+                None | Some("none") => (false, false),
+                Some("start") => (true, false),
+                Some("end") => (false, true),
+                Some("both") => (true, true),
+                Some(value) => { diagnose(format!("invalid arrows value: {}", value)); (false, false) },
+            };
+            Element::Line {
+                x1: parse_float(precursor, "x1", None),
+                y1: parse_float(precursor, "y1", None),
+                x2: parse_float(precursor, "x2", None),
+                y2: parse_float(precursor, "y2", None),
+                z: parse_int_or(precursor, "z", 0),
+                color: parse_color(precursor, "color", DEFAULT_LINE_COLOR),
+                width: parse_float(precursor, "width", Some(DEFAULT_LINE_WIDTH)),
+                style: parse_stroke_style(precursor),
+                start_arrow,
+                end_arrow,
+                from: precursor.properties.get("from").cloned(),
+                to: precursor.properties.get("to").cloned(),
+                parent: precursor.properties.get("parent").cloned(),
+                locked: parse_bool(precursor, "locked"),
+                hidden: parse_bool(precursor, "hidden"),
+            }
+        },
+
+        "rect" | "rectangle" => Element::Rect {
+            x: parse_float(precursor, "x", Some(-400.0)),
+            y: parse_float(precursor, "y", Some(0.0)),
+            width: parse_float(precursor, "width", Some(800.0)),
+            height: parse_float(precursor, "height", Some(600.0)),
+            z: parse_int_or(precursor, "z", -1),
+            rotation: parse_float_or(precursor, "rotation", 0.0),
+            color: parse_color(precursor, "color", "#00827c"),
+            id: precursor.properties.get("id").cloned(),
+            shape: parse_shape(precursor),
+            stroke: parse_optional_color(precursor, "stroke"),
+            stroke_width: parse_float(precursor, "stroke_width", Some(DEFAULT_LINE_WIDTH)),
+            style: parse_stroke_style(precursor),
+            opacity: match parse_float(precursor, "opacity", Some(DEFAULT_RECT_OPACITY)) {
+                opacity if (0.0..=1.0).contains(&opacity) => opacity,
+                opacity => { diagnose(format!("invalid opacity value: {}", opacity)); DEFAULT_RECT_OPACITY },
+            },
+            parent: precursor.properties.get("parent").cloned(),
+            locked: parse_bool(precursor, "locked"),
+            hidden: parse_bool(precursor, "hidden"),
+        },
+
+        "image" => {
+            let src = parse_string(precursor, "src", None);
+            let url = safe_url(&src).unwrap_or_default();
+            let crop = precursor.properties.get("crop").and_then(|crop| parse_crop(crop));
+
+            // Without a size, images are shown at their own (or 400px wide, if we can't tell), and
+            // without a height, with their own aspect ratio.
+            let size = local_path(&url).and_then(|path| image_dimensions(&path)).filter(|&(w, h)| w > 0 && h > 0);
+            let width = parse_float(precursor, "width", Some(size.map_or(400.0, |(w, _)| w as f64)));
+            let (crop_width, crop_height) = crop.as_ref().map_or((1.0, 1.0), |crop| (crop.width, crop.height));
+            let aspect = size.map_or(0.75, |(w, h)| h as f64 / w as f64) * crop_height / crop_width;
+
+            Element::ImageBox {
+                x: parse_float(precursor, "x", Some(0.0)),
+                y: parse_float(precursor, "y", Some(0.0)),
+                width,
+                height: parse_float(precursor, "height", Some((width * aspect).round())),
+                z: parse_int_or(precursor, "z", 0),
+                rotation: parse_float_or(precursor, "rotation", 0.0),
+                id: precursor.properties.get("id").cloned(),
+                src,
+                url,
+                crop,
+                parent: precursor.properties.get("parent").cloned(),
+                locked: parse_bool(precursor, "locked"),
+                hidden: parse_bool(precursor, "hidden"),
+            }
+        },
+
+        "ink" => {
+            let recorded = text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| decode_stroke(line).or_else(|| { diagnose(format!("invalid ink stroke: {}", line)); None }))
+                .collect::<Vec<_>>();
 
-// Takes a string and an indent level, splits at the first line less indented than the indent
-// level. Set test_first_line to true if you want the possibility of the first line being rejected
-// outright, returning ("" , text).
-fn split_scope<'a>(text: &'a str, indent: usize, test_first_line: bool) -> (&'a str, &'a str) {
-    let mut index = 0;
-    for (i, line) in text.lines().enumerate() {
-        if (i != 0 || test_first_line) && count_indent(line) < indent { break; }
-        index += line.len() + 1;
+            Element::Ink {
+                x: parse_float(precursor, "x", Some(0.0)),
+                y: parse_float(precursor, "y", Some(0.0)),
+                z: parse_int_or(precursor, "z", 0),
+                color: parse_color(precursor, "color", DEFAULT_LINE_COLOR),
+                width: parse_float(precursor, "width", Some(DEFAULT_INK_WIDTH)),
+                strokes: recorded.iter().map(|stroke| simplify_stroke(stroke)).collect(),
+                recorded,
+                raw_content: text.to_string(),
+                parent: precursor.properties.get("parent").cloned(),
+                locked: parse_bool(precursor, "locked"),
+                hidden: parse_bool(precursor, "hidden"),
+            }
+        },
+
+        "frame" => Element::Frame {
+            x: parse_float(precursor, "x", Some(0.0)),
+            y: parse_float(precursor, "y", Some(0.0)),
+            width: parse_float(precursor, "width", Some(800.0)),
+            height: parse_float(precursor, "height", Some(600.0)),
+            z: parse_int_or(precursor, "z", -1),
+            title: parse_string(precursor, "title", Some(String::new())),
+            id: precursor.properties.get("id").cloned(),
+            parent: precursor.properties.get("parent").cloned(),
+            locked: parse_bool(precursor, "locked"),
+            hidden: parse_bool(precursor, "hidden"),
+        },
+
+        _ => panic!("unknown element type: {}", precursor.type_),
     }
-    if index < text.len() { (&text[..index], &text[index..]) }
-    else { (text, "") }
 }
 
+// These all end up in the page's css, so anything that isn't exactly what's expected is refused
+// rather than passed along (colors always are, see Color). Whatever's refused is a diagnostic, and
+// kept as it was written.
+fn parse_text_style(precursor: &ElementPrecursor) -> TextStyle {
+    let property = |name: &str, valid: &dyn Fn(&str) -> bool| match precursor.properties.get(name)?.trim() {
+        value if valid(value) => Some(value.to_string()),
+        value => { diagnose(format!("invalid {} value: {}", name, value)); None },
+    };
+    let size = |name: &str, valid: fn(f64) -> bool| property(name, &|value| value.parse::<f64>().is_ok_and(|size| size.is_finite() && valid(size)))
+        .map(|value| value.parse().unwrap());
+
+    let mut style = TextStyle {
+        background: parse_optional_color(precursor, "background"),
+        border: parse_optional_color(precursor, "border"),
+        border_width: size("border_width", |width| width >= 0.0),
+        padding: size("padding", |padding| padding >= 0.0),
+        font: property("font", &|value| value.split(',').all(|family| {
+            let family = family.trim();
+            !family.is_empty() && family.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+        })),
+        font_size: size("font_size", |size| size > 0.0),
+        color: parse_optional_color(precursor, "color"),
+        align: property("align", &|value| ["left", "center", "centre", "right", "justify"].contains(&value.to_lowercase().as_str()))
+            .map(|value| match value.to_lowercase().as_str() {
+                "left" => TextAlign::Left,
+                "right" => TextAlign::Right,
+                "justify" => TextAlign::Justify,
+                _ => TextAlign::Center,
+            }),
+        class: property("class", &|value| value.split_whitespace().all(|class| {
+            !class.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+                && class.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })),
+        rejected: BTreeMap::new(),
+    };
+
+    let given = [("border_width", style.border_width.is_some()), ("padding", style.padding.is_some()), ("font", style.font.is_some()),
+                 ("font_size", style.font_size.is_some()), ("align", style.align.is_some()), ("class", style.class.is_some())];
+    style.rejected = given.into_iter()
+        .filter(|(_, parsed)| !parsed)
+        .filter_map(|(name, _)| Some((name.to_string(), precursor.properties.get(name)?.clone())))
+        .collect();
+    style
+}
 
+// Unlike the rest of an element's properties, a color that doesn't make sense is only a diagnostic.
+// It's drawn as color::INVALID, and kept as it was written, so saving doesn't lose it.
+fn parse_optional_color(precursor: &ElementPrecursor, name: &str) -> Option<Color> {
+    let value = precursor.properties.get(name)?;
+    Some(Color::parse(value).unwrap_or_else(|| {
+        diagnose(format!("invalid {} color: {}", name, value));
+        Color { written: value.clone(), css: color::INVALID.to_string() }
+    }))
+}
 
-fn parse_text_blocks(text: &str) -> Vec<TextBlock> {
+fn parse_color(precursor: &ElementPrecursor, name: &str, default: &str) -> Color {
+    parse_optional_color(precursor, name).unwrap_or_else(|| Color::parse(default).unwrap())
+}
 
+fn parse_stroke_style(precursor: &ElementPrecursor) -> StrokeStyle {
+    match precursor.properties.get("style").map(|value| value.to_lowercase()).as_deref() {
+        None | Some("solid") => StrokeStyle::Solid,
+        Some("dashed") => StrokeStyle::Dashed,
+        Some("dotted") => StrokeStyle::Dotted,
+        Some(value) => { diagnose(format!("invalid style value: {}", value)); StrokeStyle::Solid },
+    }
+}
 
+// Anything that can't be drawn as written is drawn as a plain rectangle instead.
+fn parse_shape(precursor: &ElementPrecursor) -> Shape {
+    let points = |closed: bool| {
+        let Some(value) = precursor.properties.get("points") else {
+            diagnose("unable to find points value".to_string());
+            return None;
+        };
+        let points = value.split_whitespace().map(|point| match point.split_once(',') {
+            Some((x, y)) => Some(ShapePoint { x: x.trim().parse().ok()?, y: y.trim().parse().ok()? }),
+            None => None,
+        }).collect::<Option<Vec<_>>>();
+        match points {
+            Some(points) if points.len() >= if closed { 3 } else { 2 } => Some(points),
+            _ => { diagnose(format!("invalid points value: {}", value)); None },
+        }
+    };
+    match precursor.properties.get("shape").map(|value| value.to_lowercase()).as_deref() {
+        None | Some("rect") | Some("rectangle") => Shape::Rectangle,
+        Some("rounded") => Shape::RoundedRect { radius: parse_float(precursor, "radius", Some(DEFAULT_RECT_RADIUS)) },
+        Some("ellipse") => Shape::Ellipse,
+        Some("polygon") => points(true).map_or(Shape::Rectangle, |points| Shape::Polygon { points }),
+        Some("polyline") => points(false).map_or(Shape::Rectangle, |points| Shape::Polyline { points }),
+        Some(value) => { diagnose(format!("invalid shape value: {}", value)); Shape::Rectangle },
+    }
+}
+
+// After the element `before` has been replaced with `after` (say, dragged somewhere else on the
+// page), move the ends of any lines attached to it along with it. Each end keeps the same spot
+// relative to the element, scaled if it was resized and turned if it was rotated - except
+// vertically on text boxes, which only have a top. Locked lines stay where they are. True if any
+// lines were changed.
+fn move_connections(elements: &mut BTreeMap<String, Element>, before: &Element, after: &Element) -> bool {
+    let (Some(id), Some(old), Some(new)) = (before.id(), before.bounds(), after.bounds()) else { return false; };
+    if old == new { return false; }
+
+    let (old_x, old_y, old_width, old_height, old_rotation) = old;
+    let (new_x, new_y, new_width, new_height, new_rotation) = new;
+    let reattach = |x: &mut f64, y: &mut f64| {
+        // into the old element's own unrotated coordinates, from its top left corner
+        let (sin, cos) = (-old_rotation).to_radians().sin_cos();
+        let (dx, dy) = (*x - old_x, *y - old_y);
+        let (local_x, local_y) = (dx * cos - dy * sin, dx * sin + dy * cos);
+
+        let local_x = if old_width > 0.0 { local_x * new_width / old_width } else { local_x };
+        let local_y = match (old_height, new_height) {
+            (Some(old_height), Some(new_height)) if old_height > 0.0 => local_y * new_height / old_height,
+            _ => local_y,
+        };
+
+        // and back out again from the new one
+        let (sin, cos) = new_rotation.to_radians().sin_cos();
+        *x = new_x + local_x * cos - local_y * sin;
+        *y = new_y + local_x * sin + local_y * cos;
+    };
+
+    let mut moved = false;
+    for element in elements.values_mut() {
+        if let Element::Line { x1, y1, x2, y2, from, to, locked: false, .. } = element {
+            if from.as_deref() == Some(id) { reattach(x1, y1); moved = true; }
+            if to.as_deref() == Some(id) { reattach(x2, y2); moved = true; }
+        }
+    }
+    moved
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reorder {
+    Forward,  // up past the next element
+    Backward, // down past the one below it
+    Front,    // above everything
+    Back,     // below everything
+}
+
+// Move the element at key up or down the stack of elements drawn on the page, changing as few z
+// values as it can. Elements that share a z are drawn in order of their keys, which aren't kept
+// between reloads, so the moved element always ends up strictly above whatever it's moved past (and
+// anything that would then be out of order is pushed up after it). True if anything changed.
+pub fn reorder(elements: &mut BTreeMap<String, Element>, key: &str, reorder: Reorder) -> bool {
+    let mut stack = elements.iter().map(|(key, element)| (element.z(), key.clone())).collect::<Vec<_>>();
+    stack.sort();
+
+    let Some(from) = stack.iter().position(|(_, k)| k == key) else { return false; };
+    let to = match reorder {
+        Reorder::Forward => (from + 1).min(stack.len() - 1),
+        Reorder::Backward => from.saturating_sub(1),
+        Reorder::Front => stack.len() - 1,
+        Reorder::Back => 0,
+    };
+    if to == from { return false; }
+
+    let moved = stack.remove(from);
+    stack.insert(to, moved);
+
+    // keep its z if that's already between its new neighbours, otherwise take the nearest one that is
+    let lowest = if to > 0 { stack[to - 1].0 + 1 } else { i32::MIN };
+    let highest = if to + 1 < stack.len() { stack[to + 1].0 - 1 } else { i32::MAX };
+    if lowest <= highest {
+        stack[to].0 = stack[to].0.clamp(lowest, highest);
+    } else {
+        stack[to].0 = lowest;
+        for i in to + 1..stack.len() {
+            if stack[i].0 > stack[i - 1].0 { break; }
+            stack[i].0 = stack[i - 1].0 + 1;
+        }
+    }
+
+    for (z, key) in stack {
+        if let Some(element) = elements.get_mut(&key) { *element.z_mut() = z; }
+    }
+    true
+}
+
+// The keys of everything in the frame at key: whatever names it (or a frame in it) as its parent,
+// and whatever without a parent is entirely inside it (or a frame in it). Locked elements are left
+// out, since they stay put.
+fn frame_contents(elements: &BTreeMap<String, Element>, key: &str) -> BTreeSet<String> {
+    let mut contents = BTreeSet::new();
+    let mut frames = vec![key.to_string()];
+
+    while let Some(frame_key) = frames.pop() {
+        let Some(frame @ Element::Frame { id, .. }) = elements.get(&frame_key) else { continue; };
+        let (left, top, right, bottom) = frame.extent();
+
+        for (k, element) in elements {
+            if k == key || contents.contains(k) || element.locked() { continue; }
+            let inside = match element.parent() {
+                Some(parent) => id.as_deref() == Some(parent),
+                None => {
+                    let (l, t, r, b) = element.extent();
+                    l >= left && t >= top && r <= right && b <= bottom
+                },
+            };
+            if inside {
+                contents.insert(k.clone());
+                if matches!(element, Element::Frame { .. }) { frames.push(k.clone()); }
+            }
+        }
+    }
+    contents
+}
+
+// Before the element at key is replaced with `after`, move everything that goes along with it: the
+// lines attached to it, and if it's a frame, everything in it (and the lines attached to those). True
+// if anything moved.
+pub fn move_dependents(elements: &mut BTreeMap<String, Element>, key: &str, after: &Element) -> bool {
+    let Some(before) = elements.get(key).cloned() else { return false; };
+
+    let contents = match (&before, after) {
+        (Element::Frame { x: x0, y: y0, .. }, Element::Frame { x: x1, y: y1, .. }) if (x0, y0) != (x1, y1) => {
+            let keys = frame_contents(elements, key);
+            keys.into_iter().map(|k| {
+                let element = elements.remove(&k).unwrap();
+                let mut moved = element.clone();
+                moved.translate(x1 - x0, y1 - y0);
+                (k, (element, moved))
+            }).collect::<Vec<_>>()
+        },
+        _ => vec![],
+    };
+
+    // the contents are taken out first, so lines in the frame aren't moved twice
+    let mut moved = move_connections(elements, &before, after);
+    for (_, (before, after)) in &contents {
+        moved |= move_connections(elements, before, after);
+    }
+
+    moved |= !contents.is_empty();
+    elements.extend(contents.into_iter().map(|(k, (_, after))| (k, after)));
+    moved
+}
+
+// When the file's been changed by hand, move the ends of the lines attached to anything that was
+// moved in it, just as if it had been dragged on the page. Elements are matched up by id, since keys
+// don't last between parses, and lines that were edited as well are left as they were written. True
+// if any lines were changed.
+pub fn reattach(before: &Document, after: &mut Document) -> bool {
+    let old = before.elements.values().filter_map(|element| Some((element.id()?, element))).collect::<HashMap<_, _>>();
+    let moved = after.elements.values()
+        .filter_map(|element| Some((*old.get(element.id()?)?, element)))
+        .filter(|(before, after)| !before.same_geometry(after))
+        .map(|(before, after)| (before.clone(), after.clone()))
+        .collect::<Vec<_>>();
+    if moved.is_empty() { return false; }
+
+    let untouched = before.elements.values()
+        .filter(|element| matches!(element, Element::Line { .. }))
+        .map(|element| element.write_repr())
+        .collect::<BTreeSet<_>>();
+    let (mut lines, rest) = std::mem::take(&mut after.elements).into_iter()
+        .partition::<BTreeMap<_, _>, _>(|(_, element)| untouched.contains(&element.write_repr()));
+
+    let mut changed = false;
+    for (before, after) in &moved {
+        changed |= move_connections(&mut lines, before, after);
+    }
+    after.elements = rest;
+    after.elements.extend(lines);
+    changed
+}
+
+// "0.1,0,0.5,1" -> Crop { x: 0.1, y: 0.0, width: 0.5, height: 1.0 }. Anything else is a diagnostic,
+// and the whole image is shown.
+fn parse_crop(value: &str) -> Option<Crop> {
+    let numbers = value.split(',').map(|number| number.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>();
+    match numbers.as_deref() {
+        Ok(&[x, y, width, height]) if x >= 0.0 && y >= 0.0 && width > 0.0 && height > 0.0 && x + width <= 1.0 && y + height <= 1.0 => {
+            Some(Crop { x, y, width, height })
+        },
+        _ => { diagnose(format!("invalid crop value: {}", value)); None },
+    }
+}
+
+// Tabs counted as 4 spaces.
+fn count_indent(text: &str) -> usize {
+    let mut count = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => count += 4,
+            ' ' => count += 1,
+            _ => break,
+        }
+    }
+    count
+}
 
-    // convert the precursors into TextBlocks, parsing their contents from a
-    // soup-like homogenate of characters into a deliciously chunkier form
-    fn convert_precursor(x: TextBlockPrecursor) -> Option<TextBlock> {
-        match x {
-            TextBlockPrecursor::Paragraph { text } => Some(TextBlock::Paragraph { chunks: chunk_text(&text) }),
-            TextBlockPrecursor::Header { level, text } => Some(TextBlock::Header { level, chunks: chunk_text(text) }),
-            TextBlockPrecursor::CodeBlock { lang, text: code } => {
-                let mut context = init(); // todo: don't init for every code block
-                lang.map(
-                    |lang|
-                        TextBlock::CodeBlock {
-                            text: highlight(&mut context, code, lang)
-                                    .unwrap_or("code block highlighting error".to_string())
-                        }
-                    ).or_else(|| Some(TextBlock::CodeBlock { text: code.to_string() }))
+#[test]
+fn split_scope_test() {
+    let text = r#"header
+        line 1
+            line 2
+        line 3
+    line 4"#;
+
+    let lines = text.lines().collect::<Vec<&str>>();
+    let (first, second) = split_scope(&lines, 8, false);
+
+    assert_eq!(first, ["header", "        line 1", "            line 2", "        line 3"]);
+    assert_eq!(second, ["    line 4"]);
+}
+
+
+// Takes some lines and an indent level, splits at the first line less indented than the indent
+// level. Set test_first_line to true if you want the possibility of the first line being rejected
+// outright, returning ([], lines).
+fn split_scope<'a, 'b>(lines: &'b [&'a str], indent: usize, test_first_line: bool) -> (&'b [&'a str], &'b [&'a str]) {
+    let index = lines.iter()
+        .enumerate()
+        .position(|(i, line)| (i != 0 || test_first_line) && count_indent(line) < indent)
+        .unwrap_or(lines.len());
+    lines.split_at(index)
+}
+
+// Look for the first occurrence of delimiter in some lines, returning (line index, byte offset).
+fn find_in_lines(lines: &[&str], delimiter: &str) -> Option<(usize, usize)> {
+    lines.iter().enumerate().find_map(|(i, line)| line.find(delimiter).map(|j| (i, j)))
+}
+
+fn is_blank(line: &str) -> bool { line.trim().is_empty() }
+
+// ["foo", "bar"] -> "foo bar", only copying if there's more than one line to join.
+fn join_lines<'a>(lines: &[&'a str], separator: &str) -> Cow<'a, str> {
+    match lines {
+        [line] => Cow::Borrowed(line),
+        _ => Cow::Owned(lines.join(separator)),
+    }
+}
+
+
+
+fn parse_text_blocks(text: &str, markdown: Markdown) -> Vec<TextBlock> {
+    let lines = text.lines().collect::<Vec<&str>>();
+    convert_precursors(parse_text_block_precursors(&lines, markdown), markdown)
+}
+
+fn convert_precursors(blocks: Vec<TextBlockPrecursor>, markdown: Markdown) -> Vec<TextBlock> {
+    blocks.into_iter().filter_map(|block| convert_precursor(block, markdown)).collect()
+}
+
+// convert the precursors into TextBlocks, parsing their contents from a
+// soup-like homogenate of characters into a deliciously chunkier form
+fn convert_precursor(x: TextBlockPrecursor, markdown: Markdown) -> Option<TextBlock> {
+    match x {
+        TextBlockPrecursor::Paragraph { lines } => Some(TextBlock::Paragraph { chunks: chunk_text(&join_paragraph(&lines, markdown), markdown) }),
+        TextBlockPrecursor::Header { level, lines } => Some(TextBlock::Header { level, chunks: chunk_text(&join_paragraph(&lines, markdown), markdown) }),
+        TextBlockPrecursor::CodeBlock { lang, lines } => Some(TextBlock::CodeBlock {
+            text: match lang {
+                Some(lang) => highlight_code(lang, &lines.join("\n")),
+                None => escape_html(&lines.join("\n")),
+            }
+        }),
+        TextBlockPrecursor::MathBlock { lines } => Some(TextBlock::MathBlock { text: render_math(join_lines(&lines, "\n").trim(), true) }),
+        TextBlockPrecursor::UnorderedList { items } => Some(TextBlock::UnorderedList { items: items.into_iter().map(|item| convert_precursors(item, markdown)).collect() }),
+        TextBlockPrecursor::OrderedList { start, style, delimiter, items } => Some(TextBlock::OrderedList {
+            start: start as u32,
+            style,
+            delimiter,
+            items: items.into_iter().map(|item| convert_precursors(item, markdown)).collect(),
+        }),
+        TextBlockPrecursor::BlockQuote { inner } => Some(TextBlock::BlockQuote { inner: convert_precursors(inner, markdown) }),
+        TextBlockPrecursor::Callout { kind, title, folded, inner } => Some(TextBlock::Callout {
+            kind: kind.to_lowercase(),
+            title: chunk_text(title, markdown),
+            folded,
+            inner: convert_precursors(inner, markdown),
+        }),
+        TextBlockPrecursor::DefinitionList { items } => Some(TextBlock::DefinitionList {
+            items: items.into_iter().map(|(terms, definitions)| DefinitionItem {
+                terms: terms.into_iter().map(|term| chunk_text(term.trim_end(), markdown)).collect(),
+                definitions: definitions.into_iter().map(|definition| convert_precursors(definition, markdown)).collect(),
+            }).collect()
+        }),
+        TextBlockPrecursor::Image { url, alt, title, attributes } => safe_url(url).map(|url| convert_image(url, alt, title, attributes, markdown)),
+        TextBlockPrecursor::VerticalSpace => Some(TextBlock::VerticalSpace),
+        TextBlockPrecursor::HorizontalRule => Some(TextBlock::HorizontalRule),
+        TextBlockPrecursor::SpacelessBreak => None,
+    }
+}
+
+fn convert_image(url: String, alt: &str, title: Option<&str>, attributes: Option<&str>, markdown: Markdown) -> TextBlock {
+    let mut width = None;
+    let mut height = None;
+    let mut caption = None;
+
+    for captures in IMAGE_ATTRIBUTE_REGEX.captures_iter(attributes.unwrap_or("")) {
+        let value = captures.get(2).or(captures.get(3)).map(|value| value.as_str());
+        match (&captures[1], value) {
+            ("width", Some(value)) => width = parse_image_size(value),
+            ("height", Some(value)) => height = parse_image_size(value),
+            ("caption", None) => caption = title, // {caption} on its own captions the image with its title
+            ("caption", Some(value)) => caption = Some(value),
+            _ => diagnose(format!("unknown image attribute: {}", &captures[0])),
+        }
+    }
+
+    // Fill in whatever size wasn't given from the image itself, keeping its aspect ratio, so the
+    // page doesn't jump around as images load.
+    if (width.is_none() || height.is_none()) && let Some((w, h)) = local_path(&url).and_then(|path| image_dimensions(&path)) && w > 0 && h > 0 {
+        let scale = |size: u32, from: u32, to: u32| (size as f64 * to as f64 / from as f64).round() as u32;
+        (width, height) = match (width, height) {
+            (Some(width), None) => (Some(width), Some(scale(width, w, h))),
+            (None, Some(height)) => (Some(scale(height, h, w)), Some(height)),
+            _ => (Some(w), Some(h)),
+        };
+    }
+
+    TextBlock::Image {
+        url,
+        alt: alt.to_string(),
+        title: title.map(|title| title.to_string()),
+        caption: caption.map(|caption| chunk_text(caption, markdown)).unwrap_or_default(),
+        width,
+        height,
+        thumbnail: None,
+    }
+}
+
+// Local images that are much bigger than they'll be shown get a downscaled copy to show instead
+// (see thumbnail.rs), which nothing in a text box can be wider than the box itself.
+fn add_thumbnails(blocks: &mut [TextBlock], max_width: f64) {
+    for block in blocks {
+        match block {
+            TextBlock::Image { url, width, thumbnail, .. } => *thumbnail = thumbnail_url(url, *width, max_width),
+            TextBlock::UnorderedList { items } | TextBlock::OrderedList { items, .. } => {
+                items.iter_mut().for_each(|item| add_thumbnails(item, max_width));
             },
-            TextBlockPrecursor::MathBlock { text: math } => {
-                let opts = katex::Opts::builder()
-                            .output_type(katex::OutputType::HtmlAndMathml)
-                            .display_mode(true)
-                            .throw_on_error(false)
-                            .build().unwrap();
-                let math = katex::render_with_opts(&math, &opts).unwrap();
-                Some(TextBlock::MathBlock { text: math.to_string() })
+            TextBlock::BlockQuote { inner } | TextBlock::Callout { inner, .. } => add_thumbnails(inner, max_width),
+            TextBlock::DefinitionList { items } => {
+                items.iter_mut().flat_map(|item| &mut item.definitions).for_each(|definition| add_thumbnails(definition, max_width));
             },
-            TextBlockPrecursor::UnorderedList { items } => Some(TextBlock::UnorderedList { items: items.into_iter().filter_map(|x| convert_precursor(x)).collect() }),
-            TextBlockPrecursor::OrderedList { items } => Some(TextBlock::OrderedList { items: items.into_iter().filter_map(|x| convert_precursor(x)).collect() }),
-            TextBlockPrecursor::BlockQuote { inner } => Some(TextBlock::BlockQuote { inner }),
-            TextBlockPrecursor::Image { url, alt } => Some(TextBlock::Image { url, alt }),
-            TextBlockPrecursor::VerticalSpace => Some(TextBlock::VerticalSpace),
-            TextBlockPrecursor::HorizontalRule => Some(TextBlock::HorizontalRule),
-            TextBlockPrecursor::SpacelessBreak => None,
+            _ => {},
+        }
+    }
+}
+
+// "/photos/a.jpg" -> "/thumbnail/1400/photos/a.jpg", for an image shown at 700 pixels wide
+fn thumbnail_url(url: &str, width: Option<u32>, max_width: f64) -> Option<String> {
+    let path = local_path(url)?;
+    if !thumbnail::EXTENSIONS.contains(&path.extension()?.to_str()?.to_lowercase().as_str()) { return None; }
+    let (intrinsic_width, _) = image_dimensions(&path)?;
+
+    let shown = width.unwrap_or(intrinsic_width).min(max_width.max(1.0) as u32);
+    let size = thumbnail::width(shown);
+
+    (size < intrinsic_width).then(|| format!("/thumbnail/{}{}", size, &url[..url.find(['?', '#']).unwrap_or(url.len())]))
+}
+
+// "300" or "300px"
+fn parse_image_size(value: &str) -> Option<u32> {
+    let size = value.strip_suffix("px").unwrap_or(value).parse().ok();
+    if size.is_none() { diagnose(format!("invalid image size: {}", value)); }
+    size
+}
+
+// Paragraph lines are joined with spaces, except for commonmark, where the line breaks are kept so
+// the inline parser can tell which ones are hard breaks ("foo  \n" or "foo\\\n").
+fn join_paragraph<'a>(lines: &[&'a str], markdown: Markdown) -> Cow<'a, str> {
+    match markdown {
+        Markdown::Dungeon => join_lines(lines, " "),
+        Markdown::CommonMark => match join_lines(lines, "\n") {
+            Cow::Borrowed(text) => Cow::Borrowed(text.trim_end()),
+            Cow::Owned(text) => Cow::Owned(text.trim_end().to_string()),
+        },
+    }
+}
+
+// Code blocks are rendered as html, so anything that isn't run through prism needs escaping.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Drop up to some number of columns of indentation from the start of a line.
+fn strip_indent(line: &str, columns: usize) -> &str {
+    let mut count = 0;
+    for (i, c) in line.char_indices() {
+        if count >= columns { return &line[i..]; }
+        match c {
+            '\t' => count += 4,
+            ' ' => count += 1,
+            _ => return &line[i..],
         }
     }
+    ""
+}
+
+// "#### foo ##" -> Some((4, "foo")). Closing #s are only dropped if there's a space before them.
+fn split_atx_header(line: &str) -> Option<(u8, &str)> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) { return None; }
+
+    let text = rest.trim();
+    let unclosed = text.trim_end_matches('#');
+    let text = if unclosed.is_empty() { unclosed }
+        else if unclosed.ends_with([' ', '\t']) { unclosed.trim_end() }
+        else { text };
+
+    Some((level as u8, text))
+}
+
+// three or more of the same -, * or _, with nothing else on the line but spaces.
+fn is_thematic_break(line: &str) -> bool {
+    let line = line.trim();
+    let Some(c) = line.chars().next().filter(|c| matches!(c, '-' | '*' | '_')) else { return false; };
+    line.chars().all(|x| x == c || x == ' ' || x == '\t') && line.chars().filter(|&x| x == c).count() >= 3
+}
+
+// "===" or "---" under a paragraph, making it a header.
+fn setext_level(line: &str) -> Option<u8> {
+    let line = line.trim();
+    if line.is_empty() { return None; }
+    if line.bytes().all(|b| b == b'=') { return Some(1); }
+    if line.bytes().all(|b| b == b'-') { return Some(2); }
+    None
+}
+
+// "```rust" -> Some(("```", Some("rust"))). The fence is the whole run of ` or ~, since the code
+// block is only closed by a run at least as long.
+fn split_fence(line: &str) -> Option<(&str, Option<&str>)> {
+    let c = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let length = line.bytes().take_while(|&b| b == c as u8).count();
+    if length < 3 { return None; }
+
+    let info = &line[length..];
+    if c == '`' && info.contains('`') { return None; } // actually inline code
+
+    Some((&line[..length], info.split_whitespace().next()))
+}
+
+fn list_marker(line: &str, markdown: Markdown) -> Option<ListMarker> {
+    if markdown == Markdown::Dungeon {
+        let (captures, ordered) = match UNORDERED_LIST_REGEX.captures(line) {
+            Some(captures) => (captures, false),
+            None => (ORDERED_LIST_REGEX.captures(line)?, true),
+        };
+        let marker = captures[1].trim_end();
+
+        let (number, style) = match captures.get(2) {
+            Some(label) => {
+                let (number, style) = parse_list_number(label.as_str())?;
+
+                // "A. Smith" is more likely a name than a list, so capital letters need two spaces
+                if style == ListStyle::UpperAlpha && &captures[3] == "." && captures[1].len() - marker.len() < 2 { return None; }
+
+                (Some(number), style)
+            },
+            None => (None, ListStyle::Decimal),
+        };
+
+        return Some(ListMarker {
+            ordered,
+            delimiter: marker.chars().last().unwrap(),
+            number,
+            style,
+            length: captures[0].len(),
+            indent: count_indent(line) + captures[1].len(),
+            empty: false,
+        });
+    }
+
+    // commonmark: a bullet, or up to 9 digits and a . or ), then a space or the end of the line.
+    let indent = count_indent(line);
+    if indent >= 4 { return None; }
+    let content = line.trim_start();
+    let offset = line.len() - content.len();
+
+    let digits = content.bytes().take_while(u8::is_ascii_digit).count();
+    let (ordered, width) = match content.as_bytes().first()? {
+        b'-' | b'+' | b'*' => (false, 1),
+        b'0'..=b'9' if digits <= 9 && content[digits..].starts_with(['.', ')']) => (true, digits + 1),
+        _ => return None,
+    };
+
+    let rest = &content[width..];
+    if !(rest.is_empty() || rest.starts_with([' ', '\t'])) { return None; }
+
+    // content starts after the spaces following the marker, unless there's more than four of them
+    // (then it's an indented code block, inside of the item)
+    let spaces = count_indent(rest);
+    let empty = is_blank(rest);
+    let (length, spaces) = if empty { (line.len(), 1) }
+        else if spaces > 4 { (offset + width + 1, 1) }
+        else { (line.len() - rest.trim_start().len(), spaces) };
+
+    Some(ListMarker {
+        ordered,
+        delimiter: content[width - 1..].chars().next().unwrap(),
+        number: if ordered { content[..digits].parse().ok() } else { None },
+        style: ListStyle::Decimal,
+        length,
+        indent: indent + width + spaces,
+        empty,
+    })
+}
+
+// "12" -> (12, Decimal), "iv" -> (4, LowerRoman), "C" -> (3, UpperAlpha). Lone letters are taken as
+// letters, apart from "i", which is far more often the start of a roman numbered list.
+fn parse_list_number(label: &str) -> Option<(usize, ListStyle)> {
+    if let Ok(number) = label.parse() { return Some((number, ListStyle::Decimal)); }
+
+    let upper = label.starts_with(|c: char| c.is_ascii_uppercase());
+    let lower = label.to_ascii_lowercase();
+
+    if label.len() > 1 || lower == "i" {
+        if !ROMAN_NUMERAL_REGEX.is_match(&lower) { return None; }
+
+        let value = |c| match c { 'i' => 1, 'v' => 5, 'x' => 10, 'l' => 50, 'c' => 100, 'd' => 500, _ => 1000 };
+        let digits = lower.chars().map(value).collect::<Vec<usize>>();
+        let number = digits.iter().enumerate()
+            .map(|(i, &digit)| if digits.get(i + 1).is_some_and(|&next| next > digit) { -(digit as isize) } else { digit as isize })
+            .sum::<isize>() as usize;
+
+        Some((number, if upper { ListStyle::UpperRoman } else { ListStyle::LowerRoman }))
+    } else {
+        let number = (lower.as_bytes()[0] - b'a' + 1) as usize;
+        Some((number, if upper { ListStyle::UpperAlpha } else { ListStyle::LowerAlpha }))
+    }
+}
 
-    parse_text_block_precursors(text).into_iter().filter_map(|x| convert_precursor(x)).collect()
+// ": foo" starts a definition, with the rest of it lined up under the "foo" like a list item.
+fn definition_marker(line: &str) -> Option<ListMarker> {
+    let indent = count_indent(line);
+    let rest = line.trim_start().strip_prefix(':')?;
+    if indent >= 4 || !rest.starts_with([' ', '\t']) || is_blank(rest) { return None; }
+
+    let spaces = count_indent(rest).min(4);
+    Some(ListMarker {
+        ordered: false,
+        delimiter: ':',
+        number: None,
+        style: ListStyle::Decimal,
+        length: line.len() - rest.trim_start().len(),
+        indent: indent + 1 + spaces,
+        empty: false,
+    })
+}
+
+// Whether a line would start some block other than a paragraph, given there's a paragraph open.
+// Commonmark's "lazy continuation" lets a line that doesn't carry on a blockquote or list item's
+// paragraph, as long as it isn't one of these.
+fn interrupts_paragraph(line: &str) -> bool {
+    if count_indent(line) >= 4 { return false; }
+    let line = line.trim_start();
+    is_blank(line)
+        || split_atx_header(line).is_some()
+        || split_fence(line).is_some()
+        || is_thematic_break(line)
+        || line.starts_with('>')
+        || line.starts_with("$$")
+        || list_marker(line, Markdown::CommonMark).is_some_and(|marker| !marker.empty && marker.number.map_or(true, |n| n == 1))
 }
 
 
-fn parse_text_block_precursors(mut text: &str) -> Vec<TextBlockPrecursor> {
+fn parse_text_block_precursors<'a>(lines: &[&'a str], markdown: Markdown) -> Vec<TextBlockPrecursor<'a>> {
     let mut blocks: Vec<TextBlockPrecursor> = Vec::new();
 
+    // Our own copy of the line slices (not the text itself), so that when something finishes
+    // partway through a line, the rest of that line can be put back to be parsed as usual.
+    let mut lines = lines.to_vec();
+
+    // ignore any blank lines at the start or end
+    let start = lines.iter().position(|line| !is_blank(line)).unwrap_or(lines.len());
+    let end = lines.iter().rposition(|line| !is_blank(line)).map_or(start, |i| i + 1);
+    lines.truncate(end);
+
+    let commonmark = markdown == Markdown::CommonMark;
+
     // For as long as there's still text to parse, try to parse a block.
 
     // I think TextBlock parsing is LL(7), with the longest substring needed being "^###### " (h6
     // header). Doesn't really matter given this model, but it's interesting to think about how
     // I'd do this with a more involved grammar.
 
-    text = text.trim();
+    let mut i = start;
 
-    while text.len() > 0 {
+    while i < lines.len() {
 
-        // try to parse a header -----------------------------------------------
+        let line = lines[i].trim_start();
+        let indent = count_indent(lines[i]);
+        let in_paragraph = matches!(blocks.last(), Some(TextBlockPrecursor::Paragraph { .. }));
 
-        for level in 1..=6 {
-            let header = format!("{} ", "#".repeat(level));
-            if text.starts_with(&header) {
-                let (header, rest) = split_or_end(text[level + 1..].trim_start(), "\n");
-                blocks.push(TextBlockPrecursor::Header {
-                    level: level as u8,
-                    text: header,
-                });
-                text = rest;
+        // commonmark: indented code, or more of a paragraph -------------------
+
+        // Dungeon notes don't care about indentation outside of lists, but in commonmark anything
+        // indented 4 or more is either code or the continuation of a paragraph.
+        if commonmark && indent >= 4 && !is_blank(line) {
+            if let Some(TextBlockPrecursor::Paragraph { lines: paragraph }) = blocks.last_mut() {
+                paragraph.push(line);
+                i += 1;
                 continue;
             }
-        }
 
-        // try to parse a code block with a language ---------------------------
+            let end = (i..lines.len())
+                .take_while(|&j| is_blank(lines[j]) || count_indent(lines[j]) >= 4)
+                .filter(|&j| !is_blank(lines[j]))
+                .last().unwrap() + 1;
+
+            blocks.push(TextBlockPrecursor::CodeBlock {
+                lang: None,
+                lines: lines[i..end].iter().map(|line| strip_indent(line, 4)).collect(),
+            });
+            i = end;
+            continue;
+        }
 
-        if text.starts_with("```") && let Some((lang, rest)) = split(&text[3..], "\n") {
-            if let Some((code, rest)) = split(rest, "```") {
+        // commonmark: setext header -------------------------------------------
 
-                // remove everything before the first newline
-                blocks.push(TextBlockPrecursor::CodeBlock { text: trim_one_newline(code), lang: Some(lang.trim()) });
+        // "foo\n===" or "foo\n---". In dungeon notes, the --- is a horizontal rule.
+        if commonmark && in_paragraph && let Some(level) = setext_level(line) {
+            let Some(TextBlockPrecursor::Paragraph { lines: paragraph }) = blocks.pop() else { unreachable!() };
+            blocks.push(TextBlockPrecursor::Header { level, lines: paragraph });
+            i += 1;
+            continue;
+        }
 
-                // skip forwards to the start of "code code code```[ \t]*\nHERE"
-                text = trim_start_no_newline(rest);
-                text = trim_one_newline(text);
+        // try to parse a header -----------------------------------------------
 
-                continue;
-            }
+        if let Some((level, text)) = split_atx_header(line) {
+            blocks.push(TextBlockPrecursor::Header { level, lines: vec![text] });
+            i += 1;
+            continue;
         }
 
-        // try to parse a code block with no language --------------------------
+        // try to parse a code block -------------------------------------------
 
-        if text.starts_with("```") && let Some((code, rest)) = split(&text[3..], "```") {
+        // Commonmark closes a code block with a line holding nothing but a fence at least as long
+        // as the one that opened it, or else runs to the end of whatever it's in. Dungeon notes
+        // close it at the next ``` anywhere, so "```\ncode```" works, and if it's never closed
+        // it's just text.
+        if let Some((fence, lang)) = split_fence(line) {
 
-            // remove everything before the first newline
-            blocks.push(TextBlockPrecursor::CodeBlock { text: trim_one_newline(code), lang: None });
+            let closing = if commonmark {
+                let end = (i + 1..lines.len()).find(|&j| {
+                    let closing = lines[j].trim();
+                    count_indent(lines[j]) < 4 && closing.starts_with(fence)
+                        && closing.bytes().all(|b| b == fence.as_bytes()[0])
+                }).unwrap_or(lines.len());
 
-            // skip forwards to the start of "code code code```[ \t]*\nHERE"
-            text = trim_start_no_newline(rest);
-            text = trim_one_newline(text);
+                let code = lines[i + 1..end].iter().map(|line| strip_indent(line, indent)).collect();
+                Some((code, (end + 1).min(lines.len())))
+            } else {
+                find_in_lines(&lines[i + 1..], &fence[..3]).map(|(end, column)| {
+                    let end = i + 1 + end;
 
-            continue;
+                    let mut code = lines[i + 1..end].to_vec();
+                    code.push(&lines[end][..column]); // "" if the ``` starts its line, giving a trailing \n
+
+                    // skip forwards to the start of "code code code```HERE"
+                    lines[end] = &lines[end][column + 3..];
+                    (code, if is_blank(lines[end]) { end + 1 } else { end })
+                })
+            };
+
+            if let Some((code, next)) = closing {
+                blocks.push(TextBlockPrecursor::CodeBlock { lang, lines: code });
+                i = next;
+                continue;
+            }
         }
 
         // try to parse a math block -------------------------------------------
 
-        if text.starts_with("$$") && let Some((math, rest)) = split(text[2..].trim_start(), "$$") {
+        if let Some(math) = line.strip_prefix("$$") {
+
+            // either "$$ math $$" all on one line, or spread over a few
+            let closing = match math.find("$$") {
+                Some(column) => Some((i, vec![&math[..column]], &math[column + 2..])),
+                None => find_in_lines(&lines[i + 1..], "$$").map(|(end, column)| {
+                    let end = i + 1 + end;
+                    let mut math_lines = vec![math];
+                    math_lines.extend(&lines[i + 1..end]);
+                    math_lines.push(&lines[end][..column]);
+                    (end, math_lines, &lines[end][column + 2..])
+                }),
+            };
 
-            blocks.push(TextBlockPrecursor::MathBlock { text: math.trim() });
+            if let Some((end, math_lines, rest)) = closing {
+                blocks.push(TextBlockPrecursor::MathBlock { lines: math_lines });
 
-           // skip forwards to the start of "math math math$$[ \t]*\nHERE"
-            text = trim_start_no_newline(rest);
-            text = trim_one_newline(text);
+                // skip forwards to the start of "math math math$$HERE"
+                lines[end] = rest;
+                i = if is_blank(rest) { end + 1 } else { end };
 
-            continue;
+                continue;
+            }
         }
 
         // try to parse an <hr> ------------------------------------------------
 
-        if text.starts_with("---") {
+        if is_thematic_break(line) {
             blocks.push(TextBlockPrecursor::HorizontalRule);
-            text = trim_start_no_newline(text.trim_start_matches('-'));
-            text = &text[1.min(text.len())..];
+            i += 1;
             continue;
         }
 
-        // try to parse an unordered list --------------------------------------
-
-        let mut list_items: Vec<&str> = Vec::new();
-        while let Some(captures) = UNORDERED_LIST_REGEX.captures(text) {
-            let indent_level = count_indent(text) + captures[1].len();
-            let (item_text, rest) = split_scope(text, indent_level, false);
-            list_items.push(&item_text[captures[0].len()..]);
-            text = rest;
-        }
+        // try to parse a list -------------------------------------------------
+
+        // (commonmark only lets a list interrupt a paragraph if it's not going to be empty, and
+        // an ordered one starts at 1)
+        if let Some(first) = list_marker(lines[i], markdown)
+            && !(commonmark && in_paragraph && !interrupts_paragraph(lines[i])) {
+
+            let mut items: Vec<Vec<TextBlockPrecursor>> = Vec::new();
+            let start = first.number.unwrap_or(1);
+            let style = first.style;
+            let delimiter = if first.delimiter == ')' { ListDelimiter::Paren } else { ListDelimiter::Period };
+            let mut marker = first;
+
+            loop {
+                let end = list_item_end(&lines, i, &marker, markdown);
+
+                items.push(parse_text_block_precursors(&item_lines(&lines[i..end], &marker), markdown));
+                i = end;
+
+                // Dungeon lists carry on for as long as the next line is another item. Commonmark
+                // ones can have blank lines between items, but a different bullet or delimiter
+                // starts a new list.
+                let next = if commonmark { (i..lines.len()).find(|&j| !is_blank(lines[j])) } else { Some(i) };
+
+                match next.and_then(|j| Some((j, list_marker(lines.get(j)?, markdown)?))) {
+                    Some((j, next)) if next.ordered == marker.ordered && !is_thematic_break(lines[j])
+                        && (!commonmark || next.delimiter == marker.delimiter) => {
+                        i = j;
+                        marker = next;
+                    }
+                    _ => break,
+                }
+            }
 
-        if list_items.len() > 0 {
-            blocks.push( TextBlockPrecursor::UnorderedList {
-                items: list_items
-                        .into_iter()
-                        .flat_map(|s| parse_text_block_precursors(s))
-                        .collect()
-            } );
+            blocks.push(if marker.ordered {
+                TextBlockPrecursor::OrderedList { start, style, delimiter, items }
+            } else {
+                TextBlockPrecursor::UnorderedList { items }
+            });
             continue;
         }
 
-        // try to parse an ordered list ----------------------------------------
+        // try to parse a blockquote -------------------------------------------
+
+        if line.starts_with('>') {
+            let mut inner: Vec<&str> = Vec::new();
+            let mut fenced = false; // lazy lines can't carry on a code block
 
-        let mut list_items: Vec<&str> = Vec::new();
-        while let Some(captures) = ORDERED_LIST_REGEX.captures(text) {
-            let indent_level = count_indent(text) + captures[1].len();
-            let (item_text, rest) = split_scope(text, indent_level, false);
-            list_items.push(&item_text[captures[0].len()..]);
-            text = rest;
-        }
+            while i < lines.len() {
+                if let Some(quoted) = lines[i].trim_start().strip_prefix('>')
+                    && (!commonmark || count_indent(lines[i]) < 4) {
+
+                    let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+                    if split_fence(quoted.trim_start()).is_some() { fenced = !fenced; }
+                    inner.push(quoted);
+
+                } else if commonmark && !fenced && !interrupts_paragraph(lines[i])
+                    && inner.last().is_some_and(|last| is_lazy_continuable(last)) {
+
+                    inner.push(lines[i]);
+
+                } else { break; }
+                i += 1;
+            }
 
-        if list_items.len() > 0 {
-            blocks.push( TextBlockPrecursor::OrderedList {
-                items: list_items
-                        .into_iter()
-                        .flat_map(|s| parse_text_block_precursors(s))
-                        .collect()
-            } );
+            // a blockquote starting with "[!NOTE]" is a callout
+            if let Some(captures) = CALLOUT_REGEX.captures(inner[0]) {
+                blocks.push(TextBlockPrecursor::Callout {
+                    kind: captures.get(1).unwrap().as_str(),
+                    title: captures.get(3).map_or("", |title| title.as_str()),
+                    folded: match &captures[2] { "-" => Some(true), "+" => Some(false), _ => None },
+                    inner: parse_text_block_precursors(&inner[1..], markdown),
+                });
+            } else {
+                blocks.push(TextBlockPrecursor::BlockQuote {
+                    inner: parse_text_block_precursors(&inner, markdown)
+                });
+            }
             continue;
         }
 
-        // try to parse a blockquote -------------------------------------------
+        // try to parse a definition list -------------------------------------
 
-        let mut blockquote_contents: String = "".to_string();
-        while let Some(captures) = BLOCKQUOTE_REGEX.captures(text) {
-            text = &text[captures[0].len()..];
-            blockquote_contents.push_str(&captures[1]);
-            blockquote_contents.push_str("\n");
-        }
+        // The terms are whatever paragraph came right before the first ": ", one per line. Later
+        // definitions, and later terms along with their definitions, carry on the same list. Up
+        // to one blank line is allowed between any of these.
+        let before_break = |blocks: &[TextBlockPrecursor]| match blocks.last() {
+            Some(TextBlockPrecursor::SpacelessBreak) => blocks.len() - 1,
+            _ => blocks.len(),
+        };
+
+        if let Some(marker) = definition_marker(lines[i]) && matches!(
+            blocks[..before_break(&blocks)].last(),
+            Some(TextBlockPrecursor::Paragraph { .. } | TextBlockPrecursor::DefinitionList { .. })
+        ) {
+            blocks.truncate(before_break(&blocks));
+
+            if let Some(TextBlockPrecursor::Paragraph { .. }) = blocks.last() {
+                let Some(TextBlockPrecursor::Paragraph { lines: terms }) = blocks.pop() else { unreachable!() };
+
+                if let Some(TextBlockPrecursor::DefinitionList { .. }) = blocks[..before_break(&blocks)].last() {
+                    blocks.truncate(before_break(&blocks));
+                } else {
+                    blocks.push(TextBlockPrecursor::DefinitionList { items: vec![] });
+                }
+
+                let Some(TextBlockPrecursor::DefinitionList { items }) = blocks.last_mut() else { unreachable!() };
+                items.push((terms, vec![]));
+            }
+
+            // the definition's lines are found just like a list item's
+            let end = list_item_end(&lines, i, &marker, markdown);
+            let definition = parse_text_block_precursors(&item_lines(&lines[i..end], &marker), markdown);
+
+            let Some(TextBlockPrecursor::DefinitionList { items }) = blocks.last_mut() else { unreachable!() };
+            items.last_mut().unwrap().1.push(definition);
 
-        if blockquote_contents.len() > 0 {
-            blocks.push( TextBlockPrecursor::BlockQuote {
-                inner: parse_text_blocks(&blockquote_contents)
-            } );
+            i = end;
             continue;
         }
 
         // try to parse an image -----------------------------------------------
 
-        if let Some(captures) = IMAGE_REGEX.captures(text) {
+        if let Some(captures) = IMAGE_REGEX.captures(lines[i]) {
             blocks.push(TextBlockPrecursor::Image {
-                alt: captures[1].to_string(),
-                url: captures[2].to_string(),
+                alt: captures.get(1).unwrap().as_str(),
+                url: captures.get(2).unwrap().as_str(),
+                title: captures.get(3).or(captures.get(4)).map(|title| title.as_str()),
+                attributes: captures.get(5).map(|attributes| attributes.as_str()),
             });
-            text = &text[captures[0].len()..];
+            lines[i] = &lines[i][captures[0].len()..];
+            if is_blank(lines[i]) { i += 1; }
             continue;
         }
 
         // parse either a vertical space or a paragraph ------------------------
 
-        if text.starts_with("\n") {
-            text = &text[1..];
+        if line.is_empty() {
+            i += 1;
             blocks.push(TextBlockPrecursor::SpacelessBreak);
-            while text.starts_with("\n") {
-                text = &text[1..];
-                blocks.push(TextBlockPrecursor::VerticalSpace);
+
+            // (commonmark has blank lines only ever separating blocks)
+            while i < lines.len() && is_blank(lines[i]) {
+                i += 1;
+                if !commonmark { blocks.push(TextBlockPrecursor::VerticalSpace); }
             }
         } else {
-            let (paragraph, rest) = split_or_end(text, "\n");
-
-            // if the previous block was a paragraph, merge them. Otherwise, create a new one.
-
-            if let Some(TextBlockPrecursor::Paragraph { text: prev_text }) = blocks.last_mut() {
-                // note: this could be done without a copy if we instead track the indices into an
-                // immutable text buffer inside of TextBlockPrecursor. Maybe do that later.
-
-                *prev_text = format!("{} {}", prev_text, paragraph);
+            // if the previous block was a paragraph, add this line to it. Otherwise, create a new one.
+            let line = if commonmark { line } else { line.trim_end() };
 
+            if let Some(TextBlockPrecursor::Paragraph { lines: paragraph }) = blocks.last_mut() {
+                paragraph.push(line);
             } else {
-                blocks.push(TextBlockPrecursor::Paragraph {
-                    text: paragraph.to_string(),
-                });
+                blocks.push(TextBlockPrecursor::Paragraph { lines: vec![line] });
             }
 
-            text = rest;
+            i += 1;
         }
     }
 
     blocks
 }
 
+// Whether the line before could be a paragraph that a lazy continuation line would carry on.
+fn is_lazy_continuable(line: &str) -> bool {
+    count_indent(line) < 4 // (otherwise it's code)
+        && !is_blank(line)
+        && split_atx_header(line.trim_start()).is_none()
+        && split_fence(line.trim_start()).is_none()
+        && !is_thematic_break(line)
+        && setext_level(line).is_none()
+}
+
+// A list item is the same lines, just without the "- " on the first one, and the indentation lining
+// up with it on the rest (but not on lazy lines, which are paragraph text however they're indented).
+fn item_lines<'a>(lines: &[&'a str], marker: &ListMarker) -> Vec<&'a str> {
+    let mut item = vec![&lines[0][marker.length..]];
+    item.extend(lines[1..].iter().map(|line| {
+        if count_indent(line) >= marker.indent { strip_indent(line, marker.indent) } else { line }
+    }));
+    item
+}
+
+// Index of the line just past the end of the list item starting at lines[start].
+fn list_item_end(lines: &[&str], start: usize, marker: &ListMarker, markdown: Markdown) -> usize {
+    if markdown == Markdown::Dungeon {
+        let (item_lines, _) = split_scope(&lines[start..], marker.indent, false);
+        return start + item_lines.len();
+    }
+
+    // an item starting with a blank line can only have one
+    if marker.empty && lines.get(start + 1).map_or(true, |line| is_blank(line)) { return start + 1; }
+
+    // Everything indented at least as far as the item's content is part of it, along with any
+    // blank lines in between, and lazy lines carrying on a paragraph.
+    let mut end = start + 1;
+    let mut fenced = split_fence(&lines[start][marker.length..]).is_some();
 
+    for (j, line) in lines.iter().enumerate().skip(start + 1) {
+        if is_blank(line) { continue; }
 
-// TODO: currently if something looks like a link inside of an inline codeblock
-// (or math), this will break. I could fix that by changing the order of things,
-// but then links with code or math in the title wouldn't work. The real fix is
-// to do parsing on ropes instead of strings.
+        let previous = if j - 1 == start { &lines[start][marker.length..] } else { strip_indent(lines[j - 1], marker.indent) };
 
-fn chunk_text(text: &str) -> Vec<TextChunk> { chunk_links_1(text) }
+        if count_indent(line) >= marker.indent {
+            if split_fence(strip_indent(line, marker.indent)).is_some() { fenced = !fenced; }
+        } else if fenced || interrupts_paragraph(line) || list_marker(line, markdown).is_some()
+            || !is_lazy_continuable(previous) {
+            break;
+        }
 
+        end = j + 1;
+    }
 
-// "foo [bar](baz) quux" -> ["foo ", ("bar", "baz"), " quux"]
-fn split_link_1(text: &str) -> Option<(&str, (&str, &str), &str)> {
-    let mut split = text.splitn(2, "[");
-    let before = split.next()?;
-    let mut split = split.next()?.splitn(2, "](");
-    let link_text = split.next()?;
-    let mut split = split.next()?.splitn(2, ")");
-    let link_url = split.next()?;
-    let after = split.next()?;
-    Some((before, (link_text, link_url), after))
+    end
 }
 
-// a second style of link, where we just wrap square brackets around a url.
-// TODO: add regex to only link this if the inside is a valid url.
-// "foo [bar] baz" -> ["foo ", ("bar", "bar"), " baz"]
-fn split_link_2(text: &str) -> Option<(&str, (&str, &str), &str)> {
-    let mut split = text.splitn(2, "[");
-    let before = split.next()?;
-    let mut split = split.next()?.splitn(2, "]");
-    let link = split.next()?;
-    let after = split.next()?;
-    Some((before, (link, link), after))
+
+// Inline parsing happens in two passes over the same slice of text. The first walks it left to
+// right, pulling out everything that's opaque to styling: backslash escapes, code spans, math and
+// <autolinks>, then links, bare urls and <br>s. Escapes, code and math are matched wherever the scan hits them first, so
+// nothing inside of them is ever seen as a link or style - while a link's title is tokenized again
+// in turn, so it can still contain code and math. Whatever's left is text, broken up around style
+// delimiters, which the second pass (chunk_style) pairs up.
+
+#[derive(Debug)]
+enum InlineToken<'a> {
+    Text(&'a str),
+    Delimiter(&'a str), // one of "**", "__", "~~", or "*"
+    Run { run: &'a str, can_open: bool, can_close: bool }, // commonmark's version of a delimiter
+    Code(&'a str),
+    Math(&'a str),
+    Link { title: Vec<InlineToken<'a>>, url: &'a str },
+    AutoLink(&'a str), // a url or email address, which is also the link's text
+    Break,
 }
 
-// this calls the next function in the chain on all text it outputs, as do all
-// similar style ones. Might consider converting this to a HOF type thing
-// (or the defunctionalized equivalent if that's not possible in Rust).
+fn chunk_text(text: &str, markdown: Markdown) -> Vec<TextChunk> { chunk_tokens(&tokenize(text, markdown), markdown) }
 
-fn chunk_links_1(mut text: &str) -> Vec<TextChunk> {
-    let mut chunks: Vec<TextChunk> = Vec::new();
+fn chunk_tokens(tokens: &[InlineToken], markdown: Markdown) -> Vec<TextChunk> {
+    match markdown {
+        Markdown::Dungeon => chunk_style(tokens),
+        Markdown::CommonMark => chunk_emphasis(tokens),
+    }
+}
 
-    while let Some((before, (link_text, link_url), after)) = split_link_1(text) {
-        chunks.extend(chunk_links_2(before));
-        chunks.push(TextChunk::Link {
-            title: chunk_links_2(link_text),
-            url: link_url.to_string(),
+fn tokenize(text: &str, markdown: Markdown) -> Vec<InlineToken> {
+    let mut tokens: Vec<InlineToken> = Vec::new();
+
+    // everything in text[start..i] is plain text that hasn't been pushed yet
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < text.len() {
+        let token = split_opaque(text, i).or_else(|| match text.as_bytes()[i] {
+            b'[' => split_link(text, i).and_then(|(title, url, end)| match url {
+                // (no links inside of links)
+                Some(url) => Some((InlineToken::Link { title: tokenize(title, markdown).into_iter().map(|token| match token {
+                    InlineToken::AutoLink(url) => InlineToken::Text(url),
+                    token => token,
+                }).collect(), url }, end)),
+
+                // dungeon notes can wrap a url in square brackets, anything else is just text
+                None if markdown == Markdown::Dungeon && split_autolink(title, 0) == Some(title.len()) =>
+                    Some((InlineToken::AutoLink(title), end)),
+                None => None,
+            }),
+            b'<' if text[i..].starts_with("<br>") => Some((InlineToken::Break, i + 4)),
+
+            // commonmark's hard line breaks: two spaces or a backslash at the end of a line
+            b'\n' if text[..i].ends_with("  ") => Some((InlineToken::Break, i + 1)),
+            b'\\' if text[i + 1..].starts_with('\n') => Some((InlineToken::Break, i + 2)),
+
+            // bare urls and email addresses have to start a word
+            b if b.is_ascii_alphanumeric() && (i == 0 || matches!(text.as_bytes()[i - 1], b' ' | b'\t' | b'\n' | b'*' | b'_' | b'~' | b'(')) =>
+                split_autolink(text, i).map(|end| (InlineToken::AutoLink(&text[i..end]), end)),
+            _ => None,
         });
-        text = after;
+
+        if let Some((token, end)) = token {
+            let before = if matches!(token, InlineToken::Break) { text[start..i].trim_end_matches(' ') } else { &text[start..i] };
+            tokenize_text(text, start, start + before.len(), &mut tokens, markdown);
+            tokens.push(token);
+            i = end;
+            start = end;
+        } else {
+            i += 1;
+        }
     }
 
-    chunks.extend(chunk_links_2(text));
+    tokenize_text(text, start, text.len(), &mut tokens, markdown);
 
-    chunks
+    tokens
 }
 
-fn chunk_links_2(mut text: &str) -> Vec<TextChunk> {
-    let mut chunks: Vec<TextChunk> = Vec::new();
+// If there's an escape, code span, or bit of math starting at text[start], return it along with
+// the index just past its end.
+fn split_opaque(text: &str, start: usize) -> Option<(InlineToken, usize)> {
+    match text.as_bytes()[start] {
+        b'\\' => split_escape(text, start).map(|(c, end)| (InlineToken::Text(c), end)),
+        b'`' => Some(split_code(text, start).map_or_else(
+            // an unmatched run of backticks is just text - all of it, so we don't go on to try a
+            // shorter run starting from its second backtick.
+            |end| (InlineToken::Text(&text[start..end]), end),
+            |(code, end)| (InlineToken::Code(code), end),
+        )),
+        b'$' => split_math(text, start).map(|(math, end)| (InlineToken::Math(math), end)),
+        b'<' => ANGLE_AUTOLINK_REGEX.captures(&text[start..]).map(|captures| {
+            let url = captures.get(1).unwrap();
+            (InlineToken::AutoLink(&text[start + url.start()..start + url.end()]), start + captures[0].len())
+        }),
+        _ => None,
+    }
+}
 
-    while let Some((before, (link_text, link_url), after)) = split_link_2(text) {
-        chunks.extend(chunk_breaks(before));
-        chunks.push(TextChunk::Link {
-            title: chunk_breaks(link_text),
-            url: link_url.to_string(),
-        });
-        text = after;
+// If there's a url or email address starting at text[start], the index just past its end. Any
+// punctuation at the end is left off (it's much more likely to be ending the sentence), as is a
+// ) without a matching (, so "(see www.example.com)" works.
+fn split_autolink(text: &str, start: usize) -> Option<usize> {
+    let rest = &text[start..];
+
+    if let Some(url) = URL_REGEX.find(rest) {
+        let mut url = url.as_str();
+        loop {
+            let trimmed = url.trim_end_matches(['?', '!', '.', ',', ':', ';', '*', '_', '~', '\'', '"']);
+            let trimmed = match trimmed.strip_suffix(')') {
+                Some(inner) if trimmed.matches('(').count() < trimmed.matches(')').count() => inner,
+                _ => trimmed,
+            };
+            if trimmed.len() == url.len() { break; }
+            url = trimmed;
+        }
+        return Some(start + url.len());
     }
 
-    chunks.extend(chunk_breaks(text));
+    // (only bother with the regex if there's an @ in the word at all)
+    let word = rest.split(char::is_whitespace).next().unwrap_or("");
+    if word.contains('@') && let Some(email) = EMAIL_REGEX.find(rest) {
+        let email = email.as_str().trim_end_matches('.');
+        if email.ends_with(['-', '_']) || !email.contains('@') { return None; }
+        return Some(start + email.len());
+    }
 
-    chunks
+    None
+}
+
+// The href for an autolink: "foo@example.com" -> "mailto:foo@example.com", "www.example.com" ->
+// "http://www.example.com", and anything with a scheme as is.
+fn autolink_url(text: &str) -> String {
+    if text.starts_with("www.") {
+        format!("http://{}", text)
+    } else if !text.contains(':') && text.contains('@') {
+        format!("mailto:{}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+// Only these schemes make it through to an href or src. Anything else (javascript:, data:, file:,
+// vbscript:, ...) is dropped.
+const ALLOWED_URL_SCHEMES: [&str; 5] = ["http", "https", "ftp", "mailto", "tel"];
+
+// Link and image urls go straight into an href or src on the page, and notes get shared around, so
+// none of them are trusted. Anything with a scheme has to be on the allow-list, relative paths are
+// resolved against the document's directory (which the server serves from /) and can't climb out
+// of it, and whatever's left is percent-encoded. None if the url isn't allowed, after reporting it
+// if the options ask for that.
+fn safe_url(url: &str) -> Option<String> {
+    let resolved = resolve_url(url);
+    if resolved.is_none() && OPTIONS.read().unwrap().flag_unsafe_urls {
+        diagnose(format!("disallowed url: {}", url));
+    }
+    resolved
 }
 
+fn resolve_url(url: &str) -> Option<String> {
+    // browsers ignore tabs and newlines anywhere in a url, and control characters or spaces around
+    // it, so "java\tscript:..." is still a javascript url
+    let url = url.replace(['\t', '\n', '\r'], "");
+    let url = url.trim_matches(|c: char| c <= ' ');
+
+    // "scheme:...", as long as the colon comes before any / ? or #
+    let end = url.find([':', '/', '?', '#']).unwrap_or(url.len());
+    let scheme = &url[..end];
+    if url[end..].starts_with(':')
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')) {
+        return ALLOWED_URL_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()).then(|| encode_url(url));
+    }
+
+    // "//example.com/foo" keeps the page's scheme, and "#foo" or "?foo" the page itself
+    if url.is_empty() || url.starts_with("//") || url.starts_with(['#', '?']) {
+        return Some(encode_url(url));
+    }
 
+    // anything else is a path. "./a/../b.png" -> "/b.png", and "../b.png" isn't allowed.
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+            "" | "." => {},
+            ".." => { segments.pop()?; },
+            _ => segments.push(segment),
+        }
+    }
+    let slash = if path.ends_with('/') && !segments.is_empty() { "/" } else { "" };
 
-// "foo<br>bar" -> ["foo", "bar"]
-fn split_break(text: &str) -> Option<(&str, &str)> {
-    let mut split = text.splitn(2, "<br>");
-    Some((split.next()?, split.next()?))
+    Some(encode_url(&format!("/{}{}{}", segments.join("/"), slash, rest)))
 }
 
-fn chunk_breaks(mut text: &str) -> Vec<TextChunk> {
-    let mut chunks: Vec<TextChunk> = Vec::new();
+// The file a url points to, if it's one in the document's directory.
+pub fn local_file(url: &str) -> Option<PathBuf> {
+    local_path(&resolve_url(url)?)
+}
 
-    while let Some((before, after)) = split_break(text) {
-        chunks.extend(chunk_code(before));
-        chunks.push(TextChunk::NewLine);
-        text = after;
+// The same, for a url that's already been through safe_url. "/a%20b.png" -> "<document
+// directory>/a b.png"
+fn local_path(url: &str) -> Option<PathBuf> {
+    if !url.starts_with('/') || url.starts_with("//") { return None; }
+    let path = &url[1..url.find(['?', '#']).unwrap_or(url.len())];
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        match path.get(i + 1..i + 3).filter(|_| path.as_bytes()[i] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => { bytes.push(byte); i += 3; },
+            None => { bytes.push(path.as_bytes()[i]); i += 1; },
+        }
     }
 
-    chunks.extend(chunk_code(text));
+    // "%2F..%2F" is only a ".." once it's decoded
+    let path = PathBuf::from(String::from_utf8(bytes).ok()?);
+    if !path.components().all(|component| matches!(component, Component::Normal(_))) { return None; }
 
-    chunks
+    Some(OPTIONS.read().unwrap().root.join(path))
+}
+
+// Percent-encode anything that shouldn't be in a url, leaving existing escapes alone.
+// "a b\\c%20d" -> "a%20b%5Cc%20d"
+fn encode_url(url: &str) -> String {
+    let mut encoded = String::with_capacity(url.len());
+    for (i, c) in url.char_indices() {
+        let escape = c == '%' && url.get(i + 1..i + 3).is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()));
+        if c.is_ascii_alphanumeric() || escape || ";/?:@&=+$,-_.!~*'()#".contains(c) {
+            encoded.push(c);
+        } else {
+            for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
 }
 
-// "foo `bar` baz" -> ["foo ", "bar", " baz"]
-fn split_code(text: &str) -> Option<(&str, &str, &str)> {
-    let mut split = text.splitn(2, "`");
-    let before = split.next()?;
-    let mut split = split.next()?.splitn(2, "`");
-    let code = split.next()?;
-    let after = split.next()?;
-    Some((before, code, after))
+// "\\*foo", 0 -> Some(("*", 2)). Only punctuation can be escaped, so "C:\\Users" stays as is.
+fn split_escape(text: &str, start: usize) -> Option<(&str, usize)> {
+    let c = text[start + 1..].chars().next()?;
+    if !c.is_ascii_punctuation() { return None; }
+    Some((&text[start + 1..start + 2], start + 2))
 }
 
-fn chunk_code(mut text: &str) -> Vec<TextChunk> {
-    let mut chunks: Vec<TextChunk> = Vec::new();
+// "`foo` bar", 0 -> Ok(("foo", 5)). A code span opened by n backticks is closed by the next run of
+// exactly n, so ``a ` b`` works. If both ends have a space, one gets stripped, to let code start or
+// end with a backtick: `` `foo` `` -> "`foo`". If it's never closed, Err(end of the opening run).
+fn split_code(text: &str, start: usize) -> Result<(&str, usize), usize> {
+    let run = text[start..].bytes().take_while(|&b| b == b'`').count();
+    let content_start = start + run;
+
+    let mut i = content_start;
+    while i < text.len() {
+        let length = text[i..].bytes().take_while(|&b| b == b'`').count();
+        if length == run {
+            let code = &text[content_start..i];
+            let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+                Some(stripped) if !stripped.bytes().all(|b| b == b' ') => stripped,
+                _ => code,
+            };
+            return Ok((code, i + run));
+        }
 
-    while let Some((before, code, after)) = split_code(text) {
-        chunks.extend(chunk_math(before));
-        chunks.push(TextChunk::Code { text: code.to_string(), });
-        text = after;
+        // on to the next run of backticks
+        i += length;
+        i += text[i..].find('`').unwrap_or(text.len() - i);
     }
 
-    chunks.extend(chunk_math(text));
+    Err(content_start)
+}
 
-    chunks
+// "$x^2$ foo", 0 -> Some(("x^2", 5)). To keep prices like "$5 and $10" as text, the opening $ has
+// to have something other than a space right after it, and the closing one has to have something
+// other than a space right before it, and can't be followed by a digit. \$ doesn't close it.
+fn split_math(text: &str, start: usize) -> Option<(&str, usize)> {
+    let bytes = text.as_bytes();
+    if bytes.get(start + 1).map_or(true, |b| b.is_ascii_whitespace() || *b == b'$') { return None; }
+
+    let mut i = start + 2;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' if !bytes[i - 1].is_ascii_whitespace() && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit) =>
+                return Some((&text[start + 1..i], i + 1)),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+// "[foo](bar) baz" -> Some(("foo", Some("bar"), 10)), or just "[bar] baz" -> Some(("bar", None, 5)),
+// which is only a link if there's a url inside. Escapes, code and math in the title are skipped
+// over, so a ] inside of them doesn't end it.
+fn split_link(text: &str, start: usize) -> Option<(&str, Option<&str>, usize)> {
+    let mut i = start + 1;
+    while i < text.len() && text.as_bytes()[i] != b']' {
+        i = split_opaque(text, i).map_or(i + 1, |(_, end)| end);
+    }
+
+    if i >= text.len() { return None; }
+
+    let title = &text[start + 1..i];
+    let rest = &text[i + 1..];
+
+    if let Some(url) = rest.strip_prefix('(') && let Some(length) = url.find(')') {
+        Some((title, Some(&url[..length]), i + length + 3))
+    } else {
+        Some((title, None, i + 1))
+    }
+}
+
+#[test]
+fn inline_precedence_test() {
+    let text = |s: &str| TextChunk::Text(s.to_string());
+    let code = |s: &str| TextChunk::Code { text: s.to_string() };
+    let math = |s: &str| TextChunk::Math { text: render_math(s, false) };
+
+    // code and math are opaque to everything else
+    assert_eq!(chunk_text("`[a](b)` c", Markdown::Dungeon), [code("[a](b)"), text(" c")]);
+    assert_eq!(chunk_text("`a<br>b`", Markdown::Dungeon), [code("a<br>b")]);
+    assert_eq!(chunk_text("`**a**`", Markdown::Dungeon), [code("**a**")]);
+    assert_eq!(chunk_text("$a*b*c$", Markdown::Dungeon), [math("a*b*c")]);
+    assert_eq!(chunk_text("$[a](b)$", Markdown::Dungeon), [math("[a](b)")]);
+    assert_eq!(chunk_text("$a<br>b$", Markdown::Dungeon), [math("a<br>b")]);
+
+    // but can still go inside of links and styles
+    assert_eq!(chunk_text("[a `]` $b$](c)", Markdown::Dungeon), [TextChunk::Link { title: vec![text("a "), code("]"), text(" "), math("b")], url: "/c".to_string() }]);
+    assert_eq!(chunk_text("**a `b**` c**", Markdown::Dungeon), [TextChunk::Bold { chunks: vec![text("a "), code("b**"), text(" c")] }]);
+
+    // longer runs of backticks, for code containing backticks
+    assert_eq!(chunk_text("``a ` b``", Markdown::Dungeon), [code("a ` b")]);
+    assert_eq!(chunk_text("`` `a` ``", Markdown::Dungeon), [code("`a`")]);
+    assert_eq!(chunk_text("``a`", Markdown::Dungeon), [text("``a`")]);
+
+    // dollar signs that aren't math
+    assert_eq!(chunk_text("$5 and $10", Markdown::Dungeon), [text("$5 and $10")]);
+    assert_eq!(chunk_text("between $5 and $10 each", Markdown::Dungeon), [text("between $5 and $10 each")]);
+    assert_eq!(chunk_text("$ a $", Markdown::Dungeon), [text("$ a $")]);
+    assert_eq!(chunk_text("$a$5", Markdown::Dungeon), [text("$a$5")]);
+    assert_eq!(chunk_text("$a\\$b$", Markdown::Dungeon), [math("a\\$b")]);
+
+    // backslash escapes
+    assert_eq!(chunk_text("\\*a\\*", Markdown::Dungeon), [text("*a*")]);
+    assert_eq!(chunk_text("\\*\\*a\\*\\*", Markdown::Dungeon), [text("**a**")]);
+    assert_eq!(chunk_text("\\$a$", Markdown::Dungeon), [text("$a$")]);
+    assert_eq!(chunk_text("\\`a`", Markdown::Dungeon), [text("`a`")]);
+    assert_eq!(chunk_text("\\[a](b)", Markdown::Dungeon), [text("[a](b)")]);
+    assert_eq!(chunk_text("`\\`", Markdown::Dungeon), [code("\\")]);
+    assert_eq!(chunk_text("C:\\Users", Markdown::Dungeon), [text("C:\\Users")]);
+}
+
+#[test]
+fn definition_list_test() {
+    let text = |s: &str| TextChunk::Text(s.to_string());
+    let paragraph = |s: &str| TextBlock::Paragraph { chunks: vec![text(s)] };
+
+    let blocks = parse_text_blocks("Apple\n: a fruit\n: a company\n\nOrange\nTangerine\n\n: a colour\n  and a fruit\n", Markdown::Dungeon);
+    assert_eq!(blocks, [TextBlock::DefinitionList { items: vec![
+        DefinitionItem { terms: vec![vec![text("Apple")]], definitions: vec![vec![paragraph("a fruit")], vec![paragraph("a company")]] },
+        DefinitionItem { terms: vec![vec![text("Orange")], vec![text("Tangerine")]], definitions: vec![vec![paragraph("a colour and a fruit")]] },
+    ] }]);
+
+    // terms get inline styles, definitions can hold any other block
+    let blocks = parse_text_blocks("**Term**\n:   - one\n    - two\n", Markdown::CommonMark);
+    assert_eq!(blocks, [TextBlock::DefinitionList { items: vec![DefinitionItem {
+        terms: vec![vec![TextChunk::Bold { chunks: vec![text("Term")] }]],
+        definitions: vec![vec![TextBlock::UnorderedList { items: vec![vec![paragraph("one")], vec![paragraph("two")]] }]],
+    }] }]);
+
+    // without a term, it's just a paragraph
+    assert_eq!(parse_text_blocks(": not a definition\n", Markdown::Dungeon), [paragraph(": not a definition")]);
+}
+
+#[test]
+fn callout_test() {
+    let text = |s: &str| TextChunk::Text(s.to_string());
+    let paragraph = |s: &str| TextBlock::Paragraph { chunks: vec![text(s)] };
+
+    assert_eq!(parse_text_blocks("> [!NOTE]\n> Some *text*.\n", Markdown::Dungeon), [TextBlock::Callout {
+        kind: "note".to_string(),
+        title: vec![],
+        folded: None,
+        inner: vec![TextBlock::Paragraph { chunks: vec![text("Some "), TextChunk::Italic { chunks: vec![text("text")] }, text(".")] }],
+    }]);
+
+    assert_eq!(parse_text_blocks("> [!Tip]- A `title`\n> hidden\n", Markdown::CommonMark), [TextBlock::Callout {
+        kind: "tip".to_string(),
+        title: vec![text("A "), TextChunk::Code { text: "title".to_string() }],
+        folded: Some(true),
+        inner: vec![paragraph("hidden")],
+    }]);
+
+    assert_eq!(parse_text_blocks("> [!warning]+\n", Markdown::Dungeon), [TextBlock::Callout {
+        kind: "warning".to_string(), title: vec![], folded: Some(false), inner: vec![],
+    }]);
+
+    // anything else is still a plain blockquote
+    assert_eq!(parse_text_blocks("> Not a [!NOTE]\n", Markdown::CommonMark), [TextBlock::BlockQuote {
+        inner: vec![paragraph("Not a [!NOTE]")],
+    }]);
+}
+
+#[test]
+fn ordered_list_test() {
+    let list = |text: &str| match parse_text_blocks(text, Markdown::Dungeon).as_slice() {
+        [TextBlock::OrderedList { start, style, delimiter, items }] => (*start, *style, *delimiter, items.len()),
+        blocks => panic!("not a single ordered list: {:?}", blocks),
+    };
+
+    assert_eq!(list("1. a\n2. b\n"), (1, ListStyle::Decimal, ListDelimiter::Period, 2));
+    assert_eq!(list("5) a\n6) b\n"), (5, ListStyle::Decimal, ListDelimiter::Paren, 2));
+    assert_eq!(list("iv. a\nv. b\n"), (4, ListStyle::LowerRoman, ListDelimiter::Period, 2));
+    assert_eq!(list("XIV) a\n"), (14, ListStyle::UpperRoman, ListDelimiter::Paren, 1));
+    assert_eq!(list("i. a\nii. b\niii. c\n"), (1, ListStyle::LowerRoman, ListDelimiter::Period, 3));
+    assert_eq!(list("c. a\nd. b\n"), (3, ListStyle::LowerAlpha, ListDelimiter::Period, 2));
+    assert_eq!(list("B.  a\n"), (2, ListStyle::UpperAlpha, ListDelimiter::Period, 1));
+
+    // not lists
+    let paragraph = |text: &str| matches!(parse_text_blocks(text, Markdown::Dungeon).as_slice(), [TextBlock::Paragraph { .. }]);
+    assert!(paragraph("A. Smith wrote this\n"));
+    assert!(paragraph("ab. c\n"));
+    assert!(paragraph("iiii. c\n"));
+}
+
+#[test]
+fn autolink_test() {
+    let text = |s: &str| TextChunk::Text(s.to_string());
+    let link = |s: &str, url: &str| TextChunk::Link { title: vec![text(s)], url: url.to_string() };
+
+    // bare urls, leaving off punctuation that's more likely to be part of the sentence
+    assert_eq!(chunk_text("see https://example.com/a_b.", Markdown::Dungeon), [text("see "), link("https://example.com/a_b", "https://example.com/a_b"), text(".")]);
+    assert_eq!(chunk_text("(www.example.com/a_(b))", Markdown::Dungeon), [text("("), link("www.example.com/a_(b)", "http://www.example.com/a_(b)"), text(")")]);
+    assert_eq!(chunk_text("mail foo.bar@example.com, or", Markdown::CommonMark), [text("mail "), link("foo.bar@example.com", "mailto:foo.bar@example.com"), text(", or")]);
+    assert_eq!(chunk_text("**https://example.com**", Markdown::CommonMark), [TextChunk::Bold { chunks: vec![link("https://example.com", "https://example.com")] }]);
+    assert_eq!(chunk_text("xhttps://example.com a@b", Markdown::Dungeon), [text("xhttps://example.com a@b")]);
+
+    // <autolinks>
+    assert_eq!(chunk_text("<https://example.com/*a*>", Markdown::Dungeon), [link("https://example.com/*a*", "https://example.com/*a*")]);
+    assert_eq!(chunk_text("<foo@example.com>", Markdown::CommonMark), [link("foo@example.com", "mailto:foo@example.com")]);
+
+    // square brackets only make a link if there's a url inside
+    assert_eq!(chunk_text("[https://example.com]", Markdown::Dungeon), [link("https://example.com", "https://example.com")]);
+    assert_eq!(chunk_text("[sic] and [a *b*]", Markdown::Dungeon), [text("[sic] and [a "), TextChunk::Italic { chunks: vec![text("b")] }, text("]")]);
+    assert_eq!(chunk_text("[https://example.com]", Markdown::CommonMark), [text("[https://example.com]")]);
+
+    // but not inside of links, or code
+    assert_eq!(chunk_text("[see https://a.com](https://b.com)", Markdown::Dungeon), [TextChunk::Link { title: vec![text("see https://a.com")], url: "https://b.com".to_string() }]);
+    assert_eq!(chunk_text("`https://a.com`", Markdown::Dungeon), [TextChunk::Code { text: "https://a.com".to_string() }]);
 }
 
-// "foo $bar$ baz" -> ["foo ", "bar", " baz"]
-fn split_math(text: &str) -> Option<(&str, &str, &str)> {
-    let mut split = text.splitn(2, "$");
-    let before = split.next()?;
-    let mut split = split.next()?.splitn(2, "$");
-    let code = split.next()?;
-    let after = split.next()?;
-    Some((before, code, after))
+#[test]
+fn safe_url_test() {
+    assert_eq!(resolve_url("https://example.com/a b?q=ü#x"), Some("https://example.com/a%20b?q=%C3%BC#x".to_string()));
+    assert_eq!(resolve_url("MailTo:foo@example.com"), Some("MailTo:foo@example.com".to_string()));
+    assert_eq!(resolve_url("javascript:alert(1)"), None);
+    assert_eq!(resolve_url(" java\tscript:alert(1)"), None);
+    assert_eq!(resolve_url("data:text/html,<script>"), None);
+
+    // relative paths are relative to the document, and can't leave its directory
+    assert_eq!(resolve_url("images/./a%20b.png"), Some("/images/a%20b.png".to_string()));
+    assert_eq!(resolve_url("a/../b/"), Some("/b/".to_string()));
+    assert_eq!(resolve_url("../secret.txt"), None);
+    assert_eq!(resolve_url("a/%2e%2e/%2E%2E/secret.txt"), None);
+    assert_eq!(resolve_url("/\\example.com"), Some("/%5Cexample.com".to_string()));
+    assert_eq!(resolve_url("#section"), Some("#section".to_string()));
+
+    // disallowed links are left as text, and images dropped
+    assert_eq!(chunk_text("[a *b*](javascript:go) <vbscript:x>", Markdown::Dungeon), [
+        TextChunk::Text("a ".to_string()), TextChunk::Italic { chunks: vec![TextChunk::Text("b".to_string())] }, TextChunk::Text(" vbscript:x".to_string()),
+    ]);
+    assert_eq!(parse_text_blocks("![alt](file:///etc/passwd)\n", Markdown::Dungeon), []);
 }
 
-fn chunk_math(mut text: &str) -> Vec<TextChunk> {
+#[test]
+fn line_test() {
+    let text = "!!!!Rect!x:0.0!y:0.0!width:100.0!height:50.0!z:-1!color:#00827c!id:a!!!!!!!!!!!!\n\
+                !!!!Text!x:300.0!y:0.0!width:200.0!id:b!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!\n\
+                text\n\
+                !!!!Line!x1:100.0!y1:25.0!x2:300.0!y2:10.0!style:dashed!arrows:end!from:a!to:b!!!\n";
+    let mut elements = parse(text).elements;
+
+    // everything's written back the same as it was read
+    assert_eq!(elements.values().map(|element| element.write_repr()).collect::<String>(), text);
+
+    let line_key = elements.iter().find(|(_, element)| matches!(element, Element::Line { .. })).unwrap().0.clone();
+    let line_ends = |elements: &BTreeMap<String, Element>| match &elements[&line_key] {
+        Element::Line { x1, y1, x2, y2, .. } => (*x1, *y1, *x2, *y2),
+        _ => unreachable!(),
+    };
+
+    // moving and stretching the rect keeps the start on the middle of its right side
+    let rect = |id: &str, x, y, width, height| Element::Rect {
+        x, y, width, height, z: -1, rotation: 0.0, color: Color::parse("#00827c").unwrap(), id: Some(id.to_string()),
+        shape: Shape::Rectangle, stroke: None, stroke_width: DEFAULT_LINE_WIDTH, style: StrokeStyle::Solid, opacity: 1.0, parent: None, locked: false, hidden: false,
+    };
+    assert!(move_connections(&mut elements, &rect("a", 0.0, 0.0, 100.0, 50.0), &rect("a", 50.0, 100.0, 200.0, 100.0)));
+    assert_eq!(line_ends(&elements), (250.0, 150.0, 300.0, 10.0));
+
+    // text boxes don't have a height, so the end just moves down with the top
+    let text_box = |x, y, width| Element::TextBox { x, y, width, z: 0, rotation: 0.0, id: Some("b".to_string()), parent: None, locked: false, hidden: false, style: TextStyle::default(), markdown: Markdown::Dungeon, data: vec![], raw_content: String::new() };
+    assert!(move_connections(&mut elements, &text_box(300.0, 0.0, 200.0), &text_box(200.0, 40.0, 400.0)));
+    assert_eq!(line_ends(&elements), (250.0, 150.0, 200.0, 50.0));
+
+    // rotating the rect a quarter turn around its top left corner swings the start round with it
+    let mut turned = rect("a", 50.0, 100.0, 200.0, 100.0);
+    if let Element::Rect { rotation, .. } = &mut turned { *rotation = 90.0; }
+    assert!(move_connections(&mut elements, &rect("a", 50.0, 100.0, 200.0, 100.0), &turned));
+    let (x1, y1, _, _) = line_ends(&elements);
+    assert_eq!((x1.round(), y1.round()), (0.0, 300.0));
+
+    // and nothing else is attached to anything
+    assert!(!move_connections(&mut elements, &rect("c", 0.0, 0.0, 100.0, 50.0), &rect("c", 10.0, 0.0, 100.0, 50.0)));
+
+    // moving the rect in the file brings the line along on the next reload, unless the line was changed too
+    let before = parse(text);
+    let moved = text.replace("!x:0.0!y:0.0!width:100.0", "!x:0.0!y:100.0!width:100.0");
+    let mut after = parse(&moved);
+    assert!(reattach(&before, &mut after));
+    assert!(write_elements(&after.elements).contains("!!!!Line!x1:100.0!y1:125.0!x2:300.0!y2:10.0!"));
+
+    let mut after = parse(&moved.replace("!x2:300.0!", "!x2:310.0!"));
+    assert!(!reattach(&before, &mut after));
+    assert!(write_elements(&after.elements).contains("!!!!Line!x1:100.0!y1:25.0!x2:310.0!y2:10.0!"));
+    let mut after = parse(text);
+    assert!(!reattach(&before, &mut after));
+
+    // an arrow that isn't one is a diagnostic, and no arrows
+    let document = parse(&text.replace("arrows:end", "arrows:up"));
+    assert_eq!(document.diagnostics[0].message, "invalid arrows value: up");
+    assert!(document.elements.values().any(|element| matches!(element, Element::Line { start_arrow: false, end_arrow: false, .. })));
+}
+
+#[test]
+fn image_element_test() {
+    let text = "!!!!Image!x:10.0!y:-20.5!width:300!src:https://example.com/a b.png!crop:0.25,0,0.5,1!!!!!!!!!!!!!!!!!!!!!\n";
+    let document = parse(text);
+    let [image] = document.elements.values().collect::<Vec<_>>()[..] else { panic!("not a single element") };
+
+    let Element::ImageBox { x, y, width, height, z, src, url, crop, .. } = image else { panic!("not an image: {:?}", image) };
+    assert_eq!((*x, *y, *width, *height, *z), (10.0, -20.5, 300.0, 450.0, 0)); // a half width crop of a 4:3 image
+    assert_eq!((src.as_str(), url.as_str()), ("https://example.com/a b.png", "https://example.com/a%20b.png"));
+    assert_eq!(crop, &Some(Crop { x: 0.25, y: 0.0, width: 0.5, height: 1.0 }));
+
+    // which is written back out with the height filled in, and otherwise the same
+    let written = image.write_repr();
+    assert_eq!(written, "!!!!Image!x:10.0!y:-20.5!width:300.0!height:450.0!z:0!src:https://example.com/a b.png!crop:0.25,0,0.5,1!!!\n");
+    assert_eq!(parse(&written).elements.values().next().unwrap().write_repr(), written);
+
+    let Some(Element::ImageBox { url, .. }) = parse("!!!!Image!src:javascript:alert(1)!!!!\n").elements.into_values().next() else { unreachable!() };
+    assert_eq!(url, "");
+
+    // a crop that goes off the image is a diagnostic, and none
+    let document = parse(&text.replace("crop:0.25,0,0.5,1", "crop:0.75,0,0.5,1"));
+    assert_eq!(document.diagnostics[0].message, "invalid crop value: 0.75,0,0.5,1");
+    assert!(matches!(document.elements.values().next(), Some(Element::ImageBox { crop: None, .. })));
+}
+
+#[test]
+fn image_test() {
+    let image = |url: &str, title: Option<&str>, caption: Vec<TextChunk>, width, height| TextBlock::Image {
+        url: url.to_string(), alt: "alt".to_string(), title: title.map(|title| title.to_string()), caption, width, height, thumbnail: None,
+    };
+
+    assert_eq!(parse_text_blocks("![alt](https://example.com/a (1).png)\n", Markdown::Dungeon), [image("https://example.com/a%20(1).png", None, vec![], None, None)]);
+    assert_eq!(parse_text_blocks("![alt](https://example.com/a.png \"A *title*\"){width=300 height=200px caption}\n", Markdown::Dungeon), [
+        image("https://example.com/a.png", Some("A *title*"), vec![TextChunk::Text("A ".to_string()), TextChunk::Italic { chunks: vec![TextChunk::Text("title".to_string())] }], Some(300), Some(200)),
+    ]);
+    assert_eq!(parse_text_blocks("![alt](https://example.com/a.png 'title'){caption=\"Figure 1\" width=50%}\n", Markdown::Dungeon), [
+        image("https://example.com/a.png", Some("title"), vec![TextChunk::Text("Figure 1".to_string())], None, None),
+    ]);
+}
+
+// Break up some plain text (text[start..end]) around style delimiters. A lone * is italic, while
+// doubled up **, __ and ~~ are bold, underline and strikethrough. Anything left over (the third *
+// in ***, say) is just text. Commonmark instead keeps each run of *s or _s whole, noting whether
+// it's able to open or close emphasis, going by what's on either side of it.
+fn tokenize_text<'a>(text: &'a str, start: usize, end: usize, tokens: &mut Vec<InlineToken<'a>>, markdown: Markdown) {
+    let mut start = start;
+    let mut i = start;
+
+    while i < end {
+        let c = text.as_bytes()[i];
+        if c != b'*' && c != b'_' && c != b'~' { i += 1; continue; }
+
+        let run = text[i..end].bytes().take_while(|&b| b == c).count();
+
+        if start < i { tokens.push(InlineToken::Text(&text[start..i])); }
+
+        if markdown == Markdown::CommonMark {
+            let before = text[..i].chars().next_back();
+            let after = text[i + run..].chars().next();
+            let (left, right) = flanking(before, after);
+
+            let punctuated = |c: Option<char>| c.is_some_and(is_punctuation);
+            let (can_open, can_close) = match c {
+                b'_' => (left && (!right || punctuated(before)), right && (!left || punctuated(after))),
+                b'~' if run != 2 => (false, false),
+                _ => (left, right),
+            };
+
+            tokens.push(InlineToken::Run { run: &text[i..i + run], can_open, can_close });
+        } else if c == b'*' && run == 1 {
+            tokens.push(InlineToken::Delimiter(&text[i..i + 1]));
+        } else {
+            for j in (0..run / 2).map(|j| i + 2 * j) {
+                tokens.push(InlineToken::Delimiter(&text[j..j + 2]));
+            }
+            if run % 2 == 1 { tokens.push(InlineToken::Text(&text[i + run - 1..i + run])); }
+        }
+
+        i += run;
+        start = i;
+    }
+
+    if start < end { tokens.push(InlineToken::Text(&text[start..end])); }
+}
+
+// (left flanking, right flanking) for a run of delimiters between two characters, where None is
+// the start or end of the text. https://spec.commonmark.org/0.30/#left-flanking-delimiter-run
+fn flanking(before: Option<char>, after: Option<char>) -> (bool, bool) {
+    let space = |c: Option<char>| c.map_or(true, char::is_whitespace);
+    let punctuation = |c: Option<char>| c.is_some_and(is_punctuation);
+
+    let left = !space(after) && (!punctuation(after) || space(before) || punctuation(before));
+    let right = !space(before) && (!punctuation(before) || space(after) || punctuation(after));
+    (left, right)
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+
+
+// styles don't have a precedence ordering, so I'm ending things in this single recursive function
+// that'll parse all four of em.
+
+fn chunk_style(mut tokens: &[InlineToken]) -> Vec<TextChunk> {
     let mut chunks: Vec<TextChunk> = Vec::new();
 
-    let opts = katex::Opts::builder()
-                .output_type(katex::OutputType::HtmlAndMathml)
-                .display_mode(false)
-                .throw_on_error(false)
-                .build().unwrap();
+    // grab the soonest starting style, recurse on the tokens inside of it, then carry on after it.
+    while let Some((start, delimiter)) = tokens.iter().enumerate().find_map(|(i, token)| match token {
+        InlineToken::Delimiter(delimiter) => Some((i, *delimiter)),
+        _ => None,
+    }) {
+        tokens[..start].iter().for_each(|token| push_token(&mut chunks, token, Markdown::Dungeon)); // THIS**......**....
+
+        // index of where the style ends.
+        let end = tokens[start + 1..].iter()
+            .position(|token| matches!(token, InlineToken::Delimiter(d) if *d == delimiter))
+            .map(|x| x + start + 1);
+
+        if let Some(end) = end {
+            let inner = chunk_style(&tokens[start + 1..end]);
+            chunks.push(match delimiter { // .....**THIS**......
+                "**" => TextChunk::Bold { chunks: inner },
+                "__" => TextChunk::Underline { chunks: inner },
+                "~~" => TextChunk::Strikethrough { chunks: inner },
+                _ => TextChunk::Italic { chunks: inner },
+            });
+            tokens = &tokens[end + 1..];
+        } else {
+            // .....**THIS (no closing tag), so the delimiter is just text
+            push_token(&mut chunks, &InlineToken::Text(delimiter), Markdown::Dungeon);
+            tokens = &tokens[start + 1..];
+        }
+    }
+
+    tokens.iter().for_each(|token| push_token(&mut chunks, token, Markdown::Dungeon)); //.....**......**THIS
+
+    chunks
+}
+
+// Commonmark pairs up delimiter runs by working through each one that can close emphasis, looking
+// back for the nearest run of the same character that can open it. Two from each side makes it
+// strong, one makes it emphasis, and whatever's left over of either run gets another go.
+// https://spec.commonmark.org/0.30/#process-emphasis
+fn chunk_emphasis(tokens: &[InlineToken]) -> Vec<TextChunk> {
+    enum Node<'a> {
+        Chunk(TextChunk),
+        Run { run: &'a str, count: usize, can_open: bool, can_close: bool },
+    }
 
-    while let Some((before, math, after)) = split_math(text) {
-        chunks.extend(chunk_style(before));
-        chunks.push(TextChunk::Math {
-            text: katex::render_with_opts(math, &opts).unwrap()
+    let mut nodes = tokens.iter().map(|token| match token {
+        InlineToken::Run { run, can_open, can_close } =>
+            Node::Run { run, count: run.len(), can_open: *can_open, can_close: *can_close },
+        token => {
+            let mut chunks = Vec::new();
+            push_token(&mut chunks, token, Markdown::CommonMark);
+            Node::Chunk(chunks.pop().unwrap())
+        },
+    }).collect::<Vec<Node>>();
+
+    let mut closer = 0;
+    while closer < nodes.len() {
+        let Node::Run { run, count, can_open: closer_open, can_close: true } = nodes[closer] else { closer += 1; continue; };
+        let c = run.as_bytes()[0];
+
+        let opener = (0..closer).rev().find(|&j| match nodes[j] {
+            Node::Run { run: opener, count: opener_count, can_open: true, can_close: opener_close } =>
+                opener.as_bytes()[0] == c && opener_count > 0
+                    // "rule of 3": if either side could go both ways, the two runs can't add up to
+                    // a multiple of 3 unless both of them are.
+                    && !((opener_close || closer_open) && (opener.len() + run.len()) % 3 == 0
+                        && !(opener.len() % 3 == 0 && run.len() % 3 == 0)),
+            _ => false,
         });
-        text = after;
+
+        let Some(opener) = opener else { closer += 1; continue; };
+        let Node::Run { count: opener_count, .. } = nodes[opener] else { unreachable!() };
+
+        let used = if count >= 2 && opener_count >= 2 { 2 } else { 1 };
+
+        let mut inner = Vec::new();
+        for node in nodes.drain(opener + 1..closer) { push_node(&mut inner, node); }
+        nodes.insert(opener + 1, Node::Chunk(match (c, used) {
+            (b'~', _) => TextChunk::Strikethrough { chunks: inner },
+            (_, 2) => TextChunk::Bold { chunks: inner },
+            _ => TextChunk::Italic { chunks: inner },
+        }));
+        closer = opener + 2;
+
+        for j in [closer, opener] {
+            if let Node::Run { count, .. } = &mut nodes[j] { *count -= used; }
+            if matches!(nodes[j], Node::Run { count: 0, .. }) {
+                nodes.remove(j);
+                if j == opener { closer -= 1; }
+            }
+        }
     }
 
-    chunks.extend(chunk_style(text));
+    fn push_node(chunks: &mut Vec<TextChunk>, node: Node) {
+        match node {
+            Node::Chunk(chunk) => push_chunk(chunks, chunk),
+            Node::Run { run, count, .. } => push_chunk(chunks, TextChunk::Text(run[..count].to_string())),
+        }
+    }
 
+    let mut chunks = Vec::new();
+    for node in nodes { push_node(&mut chunks, node); }
     chunks
 }
 
+fn push_token(chunks: &mut Vec<TextChunk>, token: &InlineToken, markdown: Markdown) {
+    let (title, url) = match token {
+        InlineToken::Text(text) | InlineToken::Delimiter(text) | InlineToken::Run { run: text, .. } => return push_chunk(chunks, TextChunk::Text(text.to_string())),
+        InlineToken::Code(code) => return push_chunk(chunks, TextChunk::Code { text: code.replace('\n', " ") }),
+        InlineToken::Math(math) => return push_chunk(chunks, TextChunk::Math { text: render_math(math, false) }),
+        InlineToken::Break => return push_chunk(chunks, TextChunk::NewLine),
+        InlineToken::Link { title, url } => (chunk_tokens(title, markdown), Cow::Borrowed(*url)),
+        InlineToken::AutoLink(url) => (vec![TextChunk::Text(url.to_string())], Cow::Owned(autolink_url(url))),
+    };
+
+    // a link to somewhere that isn't allowed is left as just its text
+    match safe_url(&url) {
+        Some(url) => push_chunk(chunks, TextChunk::Link { title, url }),
+        None => title.into_iter().for_each(|chunk| push_chunk(chunks, chunk)),
+    }
+}
 
+// push a chunk, merging neighbouring bits of text.
+fn push_chunk(chunks: &mut Vec<TextChunk>, chunk: TextChunk) {
+    match (chunks.last_mut(), chunk) {
+        (Some(TextChunk::Text(last)), TextChunk::Text(text)) => last.push_str(&text),
+        (_, chunk) => chunks.push(chunk),
+    }
+}
 
 
 
+// Render blocks the way the commonmark spec's examples do, to compare against them.
+#[cfg(test)]
+fn render_html(blocks: &[TextBlock]) -> String {
+    fn chunks(chunks: &[TextChunk]) -> String { chunks.iter().map(chunk).collect() }
+    fn chunk(chunk: &TextChunk) -> String {
+        match chunk {
+            TextChunk::Link { title, url } => format!("<a href=\"{}\">{}</a>", escape_html(url), chunks(title)),
+            TextChunk::Code { text } => format!("<code>{}</code>", escape_html(text)),
+            TextChunk::Math { text } => text.clone(),
+            TextChunk::Bold { chunks: inner } => format!("<strong>{}</strong>", chunks(inner)),
+            TextChunk::Italic { chunks: inner } => format!("<em>{}</em>", chunks(inner)),
+            TextChunk::Underline { chunks: inner } => format!("<u>{}</u>", chunks(inner)),
+            TextChunk::Strikethrough { chunks: inner } => format!("<del>{}</del>", chunks(inner)),
+            TextChunk::Text(text) => escape_html(text),
+            TextChunk::NewLine => "<br />".to_string(),
+        }
+    }
+    let items = |items: &[Vec<TextBlock>]| items.iter().map(|item| format!("<li>{}</li>", render_html(item))).collect::<String>();
+
+    blocks.iter().map(|block| match block {
+        TextBlock::Paragraph { chunks: inner } => format!("<p>{}</p>", chunks(inner)),
+        TextBlock::Header { level, chunks: inner } => format!("<h{}>{}</h{}>", level, chunks(inner), level),
+        TextBlock::CodeBlock { text } => format!("<pre><code>{}</code></pre>", text),
+        TextBlock::MathBlock { text } => format!("<div>{}</div>", text),
+        TextBlock::UnorderedList { items: inner } => format!("<ul>{}</ul>", items(inner)),
+        TextBlock::OrderedList { start: 1, items: inner, .. } => format!("<ol>{}</ol>", items(inner)),
+        TextBlock::OrderedList { start, items: inner, .. } => format!("<ol start=\"{}\">{}</ol>", start, items(inner)),
+        TextBlock::BlockQuote { inner } => format!("<blockquote>{}</blockquote>", render_html(inner)),
+        TextBlock::Callout { kind, title, inner, .. } => format!("<div class=\"callout-{}\"><p>{}</p>{}</div>", kind, chunks(title), render_html(inner)),
+        TextBlock::DefinitionList { items } => format!("<dl>{}</dl>", items.iter().map(|item| {
+            item.terms.iter().map(|term| format!("<dt>{}</dt>", chunks(term))).collect::<String>()
+                + &item.definitions.iter().map(|definition| format!("<dd>{}</dd>", render_html(definition))).collect::<String>()
+        }).collect::<String>()),
+        TextBlock::Image { url, alt, title, .. } => match title {
+            Some(title) => format!("<p><img src=\"{}\" alt=\"{}\" title=\"{}\" /></p>", escape_html(url), escape_html(alt), escape_html(title)),
+            None => format!("<p><img src=\"{}\" alt=\"{}\" /></p>", escape_html(url), escape_html(alt)),
+        },
+        TextBlock::VerticalSpace => String::new(),
+        TextBlock::HorizontalRule => "<hr />".to_string(),
+    }).collect()
+}
 
+// "!!!!Rect!x:0.0!" -> the header line as it's written, padded out to 80 characters
+#[cfg(test)]
+fn padded_header(header: &str) -> String {
+    format!("{:!<80}\n", header.to_string() + "!!")
+}
 
-// last bunch of styles don't have a precedence ordering, so I'm ending the
-// chain in this single recursive function that'll parse all four of em.
+// The spec examples (from version 0.30) for the parts of markdown that dungeon notes support,
+// checked against the commonmark setting. Those left over are either things we don't do at all
+// (html, entities, link reference definitions, tabs inside of list markers, ...) or rendering
+// details there's nowhere to put (tight vs loose lists, ordered list start numbers).
+#[cfg(test)]
+const COMMONMARK_KNOWN_FAILURES: &[usize] = &[
+    21, 308, 309, 344, 474, 475, 476,       // inline html and html comments
+    22, 23, 317,                            // link titles and link reference definitions
+    93,                                     // a lazy "===" is text, not a setext underline
+];
 
-fn chunk_style(text: &str) -> Vec<TextChunk> {
+#[test]
+fn commonmark_spec_test() {
+    #[derive(Deserialize)]
+    struct Example { example: usize, section: String, markdown: String, html: String }
+
+    let examples: Vec<Example> = serde_json::from_str(include_str!("../tests/commonmark.json")).unwrap();
+
+    // Whitespace between tags, prism's highlighting, and whether list items wrap their paragraphs
+    // in <p>s (tight or loose lists) don't matter here.
+    lazy_static! {
+        static ref SPACE: Regex = Regex::new(r"\s+").unwrap();
+        static ref TAG_SPACE: Regex = Regex::new(r"\s*(<[^>]*>)\s*").unwrap();
+        static ref HIGHLIGHTING: Regex = Regex::new(r#"</?span[^>]*>| class="language-[^"]*""#).unwrap();
+        static ref TAG: Regex = Regex::new(r"<(/?)(\w+)[^>]*?(/?)>").unwrap();
+    }
+    let normalize = |html: &str| {
+        let html = HIGHLIGHTING.replace_all(html, "");
+        let html = SPACE.replace_all(&html, " ");
+        let html = TAG_SPACE.replace_all(&html, "$1");
+
+        let mut parents: Vec<String> = Vec::new();
+        TAG.replace_all(&html, |caps: &regex::Captures| {
+            let (closing, name, empty) = (&caps[1] == "/", &caps[2], &caps[3] == "/");
+            if closing { parents.pop(); } else if !empty && name != "hr" && name != "br" { parents.push(name.to_string()); }
+
+            let in_item = parents.iter().rev().nth(if closing { 0 } else { 1 }).is_some_and(|parent| parent == "li");
+            if name == "p" && in_item { String::new() } else { caps[0].to_string() }
+        }).into_owned()
+    };
+
+    let mut failures = Vec::new();
+    let mut sections: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+
+    for example in &examples {
+        let blocks = parse_text_blocks(&example.markdown, Markdown::CommonMark);
+        let passed = normalize(&render_html(&blocks)) == normalize(&example.html);
+
+        let section = sections.entry(&example.section).or_default();
+        section.1 += 1;
+        if passed { section.0 += 1; } else { failures.push(example.example); }
+
+        if passed == COMMONMARK_KNOWN_FAILURES.contains(&example.example) {
+            println!("example {} ({}):\n{}\nexpected: {}\n     got: {}\n", example.example, example.section,
+                     example.markdown, normalize(&example.html), normalize(&render_html(&blocks)));
+        }
+    }
 
-    if text.is_empty() { return vec![]; }
+    for (section, (passed, total)) in sections { println!("{:>3}/{:<3} {}", passed, total, section); }
 
-    enum Style { Bold, Italic, Strike, Under }
+    let mut known_failures = COMMONMARK_KNOWN_FAILURES.to_vec();
+    known_failures.sort();
+    assert_eq!(failures, known_failures);
+}
 
-    // you could definitely do this faster by traversing forwards once with a
-    // state machine, instead of scanning with all 4 and choosing the minimum.
-    // Maybe change to that later.
-    let bold_index = |text: &str| text.find("**").map(|x| (x, Style::Bold));
-    let under_index = |text: &str| text.find("__").map(|x| (x, Style::Under));
-    let strike_index = |text: &str| text.find("~~").map(|x| (x, Style::Strike));
-    let italic_index = |text: &str| { ITALIC_REGEX.captures(&text).map(
-            |x| (x.get(1).unwrap().start(), Style::Italic)
-    ) };
 
-    // grab the soonest starting style, and recurse on the text before, after, and inside of it.
-    if let Some((min_index, min_style)) = [bold_index(text), under_index(text), strike_index(text), italic_index(text)]
-                                          .into_iter()
-                                          .filter_map(|x| x)
-                                          .min_by_key(|x| x.0) {
 
-        // index of where the style ends.
-        let end_index = match min_style {
-            Style::Bold => bold_index(&text[min_index + 2..]).map(|x| x.0 + min_index + 2),
-            Style::Under => under_index(&text[min_index + 2..]).map(|x| x.0 + min_index + 2),
-            Style::Strike => strike_index(&text[min_index + 2..]).map(|x| x.0 + min_index + 2),
-            Style::Italic => italic_index(&text[min_index + 1..]).map(|x| x.0 + min_index + 1),
-        };
+#[cfg(test)]
+const BENCH_TEXT: &str = r#"This paragraph should have zero vertical space between it and the start of the
+element. The next paragraph should have no additional vertical space between it
+and the current, beyond what you would expect from a `</p><p>` junction.
+
+The following line styles should be functional: *italic*, **bold**, `code`,
+__underline__, and ~~strikethrough~~. Single underscores like _this_ and single
+tilde like ~this~ should be displayed as is.
+
+
+These<br>should<br>be<br>separate<br>lines.
+# Inline stuff should work in a heading:
+## *italic*, **bold**, `code`, __underline__, and ~~strikethrough~~.
+
+The following should be a list of items
+- indent level 0
+  , more indent level 0
+- indent level 0
+    - indent level 1
+      , more indent level 1
+    - indent level 1
+- indent level 0
+  , more indent level 0
+    - indent level 1
+        - indent level 2
+        - indent level 2
+    - indent level 1
+
+The following should be a numbered list
+1. foo
+2. bar
+    1. baz
+    2. quux
+
+> This is a blockquote
+> and this is the second line
+>> Where as this is nested
+> and this is back to the first level
+
+Links should be [able to contain *inline* ~~styles~~ and `code`](https://example.com).
+Links can also work just by using square brackets around the URL: [https://example.com]
+---
+![image](https://github.com/FraserLee/dotfiles/blob/master/test.jpeg?raw=true)
+
+"#;
+
+#[bench]
+fn parse_text_blocks_bench(b: &mut test::Bencher) {
+    let text = BENCH_TEXT.repeat(50);
+    b.iter(|| parse_text_blocks(&text, Markdown::Dungeon));
+}
 
-        let mut chunks = chunk_style(&text[..min_index]); // THIS**......**....
+// one paragraph, thousands of lines long
+#[bench]
+fn parse_long_paragraph_bench(b: &mut test::Bencher) {
+    let text = "some words, *a bit of style*, and a `code span` on each line\n".repeat(2000);
+    b.iter(|| parse_text_blocks(&text, Markdown::Dungeon));
+}
 
-        if let Some(end_index) = end_index {
-            chunks.push(match min_style { // .....**THIS**......
-                Style::Bold => TextChunk::Bold { chunks: chunk_style(&text[min_index + 2..end_index]) },
-                Style::Italic => TextChunk::Italic { chunks: chunk_style(&text[min_index + 1..end_index]) },
-                Style::Under => TextChunk::Underline { chunks: chunk_style(&text[min_index + 2..end_index]) },
-                Style::Strike => TextChunk::Strikethrough { chunks: chunk_style(&text[min_index + 2..end_index]) },
-            });
-            chunks.extend( //.....**......**THIS
-                chunk_style(&text[end_index + match min_style {
-                        Style::Bold | Style::Under | Style::Strike => 2,
-                        Style::Italic => 1,
-                }..])
-            );
-        } else {
-            chunks.extend(chunk_style( match min_style { // .....**THIS (no closing tag)
-                Style::Bold => &text[min_index + 2..],
-                Style::Italic => &text[min_index + 1..],
-                Style::Under => &text[min_index + 2..],
-                Style::Strike => &text[min_index + 2..],
-            }));
-        }
+#[test]
+fn markdown_mode_test() {
+    let mode = |text: &str| match parse(text).elements.into_values().next() {
+        Some(Element::TextBox { markdown, .. }) => markdown,
+        _ => unreachable!(),
+    };
+    assert_eq!(mode("!!!!Text!x:0.0!y:0.0!!!!\n"), Markdown::Dungeon);
+    assert_eq!(mode("!!!!Text!x:0.0!y:0.0!markdown:CommonMark!!!!\n"), Markdown::CommonMark);
+
+    // anything else is a diagnostic, and the usual parser
+    let document = parse("!!!!Text!x:0.0!y:0.0!markdown:gfm!!!!\n");
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["invalid markdown value: gfm"]);
+    assert_eq!(mode("!!!!Text!x:0.0!y:0.0!markdown:gfm!!!!\n"), Markdown::Dungeon);
+}
 
-        chunks
-    } else {
-        vec![TextChunk::Text(text.to_string())]
+#[test]
+fn ink_test() {
+    // a pen stroke that gets lighter towards its end, and a mouse one with no pressure at all
+    let header = padded_header("!!!!Ink!x:100.0!y:50.0!z:2!width:4.0!");
+    let text = header.clone() + "0,0,80 10,0 10,0 10,0 10,0,-1 10,0 10,0,-9 10,5,-10\n\
+                \n\
+                -15,25 100,0 100,0 100,0\n";
+    let document = parse(&text);
+    let [ink] = document.elements.values().collect::<Vec<_>>()[..] else { panic!("not a single element") };
+
+    let Element::Ink { x, y, z, color, width, strokes, recorded, .. } = ink else { panic!("not ink: {:?}", ink) };
+    assert_eq!((*x, *y, *z, color.written.as_str(), *width), (100.0, 50.0, 2, DEFAULT_LINE_COLOR, 4.0));
+    assert_eq!(recorded.iter().map(Vec::len).collect::<Vec<_>>(), [8, 4]);
+    assert_eq!(recorded[0][5], InkPoint { x: 5.0, y: 0.0, pressure: 0.79 });
+    assert_eq!(recorded[1][0], InkPoint { x: -1.5, y: 2.5, pressure: DEFAULT_INK_PRESSURE });
+
+    // straight runs are only drawn from their ends, but not where the pressure starts to drop
+    let point = |x, y, pressure| InkPoint { x, y, pressure };
+    assert_eq!(strokes[0], [point(0.0, 0.0, 0.8), point(5.0, 0.0, 0.79), point(7.0, 0.5, 0.6)]);
+    assert_eq!(strokes[1], [point(-1.5, 2.5, 0.5), point(28.5, 2.5, 0.5)]);
+
+    // it's written back exactly as it was, blank line and all
+    assert_eq!(ink.write_repr(), text);
+
+    // strokes that can't be read are skipped, with a diagnostic, but kept in the file
+    let text = header.clone() + "0,0,80 10,0\n0,0 1,x\n";
+    let document = parse(&text);
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["invalid ink stroke: 0,0 1,x"]);
+    let Some(Element::Ink { recorded, .. }) = document.elements.values().next() else { panic!("not ink") };
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(document.elements.values().next().unwrap().write_repr(), text);
+
+    // and any strokes that weren't read from the file are written out newly encoded, with the
+    // mouse one getting the pressure it was read with
+    let Element::Ink { x, y, z, color, width, strokes, recorded, raw_content: _, parent, locked, hidden } = ink.clone() else { unreachable!() };
+    let redrawn = Element::Ink { x, y, z, color, width, strokes, recorded, raw_content: String::new(), parent, locked, hidden };
+    let written = redrawn.write_repr();
+    assert_eq!(written, header + "0,0,80 10,0 10,0 10,0 10,0,-1 10,0 10,0,-9 10,5,-10\n\
+                         -15,25,50 100,0 100,0 100,0\n");
+    assert_eq!(parse(&written).elements.values().next().unwrap().write_repr(), written);
+}
+
+#[test]
+fn shape_test() {
+    let shape_of = |properties: &str| {
+        let text = format!("!!!!Rect!x:0.0!y:0.0!width:200.0!height:100.0!z:-1!color:#00827c!{}!!!\n", properties);
+        let document = parse(&text);
+        let rect = document.elements.values().next().unwrap().clone();
+        assert_eq!(rect.write_repr(), padded_header(&(text.trim_end().trim_end_matches('!').to_string() + "!")));
+        rect
+    };
+
+    let Element::Rect { shape, stroke, stroke_width, style, opacity, .. } = shape_of("shape:rounded!stroke:#ffffff!style:dashed!opacity:0.5") else { panic!() };
+    assert_eq!((shape, stroke.map(|stroke| stroke.written).as_deref(), stroke_width, style, opacity), (Shape::RoundedRect { radius: 8.0 }, Some("#ffffff"), 2.0, StrokeStyle::Dashed, 0.5));
+
+    let Element::Rect { shape, .. } = shape_of("shape:polygon!points:0.5,0 1,1 0,1!stroke_width:3.0") else { panic!() };
+    let point = |x, y| ShapePoint { x, y };
+    assert_eq!(shape, Shape::Polygon { points: vec![point(0.5, 0.0), point(1.0, 1.0), point(0.0, 1.0)] });
+
+    // plain rectangles stay the way they've always been written
+    let Element::Rect { shape, stroke, .. } = shape_of("id:box") else { panic!() };
+    assert_eq!((shape, stroke), (Shape::Rectangle, None));
+    let Element::Rect { shape, .. } = shape_of("shape:rounded!radius:4.0") else { panic!() };
+    assert_eq!(shape, Shape::RoundedRect { radius: 4.0 });
+
+    // anything that can't be drawn is a diagnostic, and the default instead
+    let text = padded_header("!!!!Rect!x:0.0!y:0.0!width:200.0!height:100.0!z:-1!color:#00827c!shape:polygon!points:0,0 1!style:wavy!opacity:2!");
+    let document = parse(&text);
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(),
+               ["invalid points value: 0,0 1", "invalid style value: wavy", "invalid opacity value: 2"]);
+    let Some(Element::Rect { shape, style, opacity, .. }) = document.elements.into_values().next() else { panic!() };
+    assert_eq!((shape, style, opacity), (Shape::Rectangle, StrokeStyle::Solid, 1.0));
+    let document = parse(&padded_header("!!!!Rect!x:0.0!y:0.0!width:200.0!height:100.0!shape:hexagon!"));
+    assert_eq!(document.diagnostics[0].message, "invalid shape value: hexagon");
+
+    // and rotation only when there is some
+    let text = padded_header("!!!!Rect!x:0.0!y:0.0!width:200.0!height:100.0!z:-1!rotation:-12.5!color:#00827c!");
+    let rect = parse(&text).elements.into_values().next().unwrap();
+    assert!(matches!(rect, Element::Rect { rotation, .. } if rotation == -12.5));
+    assert_eq!(rect.write_repr(), text);
+    let rect = parse(&text.replace("rotation:-12.5", "rotation:15")).elements.into_values().next().unwrap();
+    assert!(rect.write_repr().contains("!z:-1!rotation:15.0!"));
+
+    // and none, with a diagnostic, when it isn't a number
+    let document = parse(&text.replace("rotation:-12.5", "rotation:abc"));
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["invalid rotation value: abc"]);
+    assert!(matches!(document.elements.values().next(), Some(Element::Rect { rotation, .. }) if *rotation == 0.0));
+}
+
+#[test]
+fn reorder_test() {
+    let rect = |z| Element::Rect {
+        x: 0.0, y: 0.0, width: 10.0, height: 10.0, z, rotation: 0.0, color: Color::parse("#00827c").unwrap(), id: None,
+        shape: Shape::Rectangle, stroke: None, stroke_width: DEFAULT_LINE_WIDTH, style: StrokeStyle::Solid, opacity: 1.0, parent: None, locked: false, hidden: false,
+    };
+    let mut elements = [("a", 0), ("b", 0), ("c", 3), ("d", 5)].into_iter()
+        .map(|(key, z)| (key.to_string(), rect(z)))
+        .collect::<BTreeMap<_, _>>();
+    fn stack(elements: &BTreeMap<String, Element>) -> Vec<(i32, &str)> {
+        let mut stack = elements.iter().map(|(key, element)| (element.z(), key.as_str())).collect::<Vec<_>>();
+        stack.sort();
+        stack
     }
+
+    // where there's a gap, only the moved element changes
+    assert!(reorder(&mut elements, "d", Reorder::Backward));
+    assert_eq!(stack(&elements), [(0, "a"), (0, "b"), (2, "d"), (3, "c")]);
+    assert!(reorder(&mut elements, "a", Reorder::Forward));
+    assert_eq!(stack(&elements), [(0, "b"), (1, "a"), (2, "d"), (3, "c")]);
+
+    // and without one, everything above it is pushed up to make room
+    assert!(reorder(&mut elements, "b", Reorder::Forward));
+    assert_eq!(stack(&elements), [(1, "a"), (2, "b"), (3, "d"), (4, "c")]);
+
+    assert!(reorder(&mut elements, "c", Reorder::Back));
+    assert_eq!(stack(&elements), [(0, "c"), (1, "a"), (2, "b"), (3, "d")]);
+    assert!(reorder(&mut elements, "b", Reorder::Front));
+    assert_eq!(stack(&elements), [(0, "c"), (1, "a"), (3, "d"), (4, "b")]);
+
+    // the top can't go any higher
+    assert!(!reorder(&mut elements, "b", Reorder::Forward));
+    assert!(!reorder(&mut elements, "missing", Reorder::Front));
+
+    // a z that isn't a whole number is a diagnostic, and the default for the element
+    let document = parse(&padded_header("!!!!Rect!x:0.0!y:0.0!width:10.0!height:10.0!z:top!"));
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["invalid z value: top"]);
+    assert_eq!(document.elements.values().next().unwrap().z(), -1);
+}
+
+#[test]
+fn frame_test() {
+    let text = [
+        padded_header("!!!!Frame!x:100.0!y:100.0!width:500.0!height:400.0!z:-1!title:Outer!id:f!"),
+        padded_header("!!!!Frame!x:10.0!y:20.0!width:200.0!height:200.0!z:-1!id:g!parent:f!"),
+        padded_header("!!!!Text!x:5.0!y:5.0!width:100.0!id:t!parent:g!"),
+        "hello\n".to_string(),
+        padded_header("!!!!Rect!x:300.0!y:300.0!width:50.0!height:50.0!z:-1!color:#00827c!"),
+        padded_header("!!!!Rect!x:550.0!y:300.0!width:100.0!height:50.0!z:-1!color:#00827c!"),
+        padded_header("!!!!Line!x1:0.0!y1:0.0!x2:115.0!y2:125.0!to:t!"),
+    ].concat();
+
+    let document = parse(&text);
+    assert!(document.diagnostics.is_empty());
+    let mut elements = document.elements;
+    let key = |i: usize| elements.keys().find(|key| key.starts_with(&format!("{}_", i))).unwrap().clone();
+    let (outer, inner, text_box, inside, overlapping, line) = (key(0), key(1), key(2), key(3), key(4), key(5));
+
+    // on the page, everything's where it is after adding up the frames it's in
+    let position = |element: &Element| element.bounds().map(|(x, y, ..)| (x, y));
+    assert_eq!(position(&elements[&inner]), Some((110.0, 120.0)));
+    assert_eq!(position(&elements[&text_box]), Some((115.0, 125.0)));
+
+    // but it's written back the same as it was read
+    assert_eq!(write_elements(&elements), text);
+
+    // moving the outer frame moves the inner frame and its text box, the rect entirely inside it,
+    // and the end of the line attached to the text box - but not the rect hanging over its edge
+    let mut moved = elements[&outer].clone();
+    moved.translate(50.0, -10.0);
+    assert!(move_dependents(&mut elements, &outer, &moved));
+    elements.insert(outer.clone(), moved);
+
+    assert_eq!(position(&elements[&text_box]), Some((165.0, 115.0)));
+    assert_eq!(position(&elements[&inside]), Some((350.0, 290.0)));
+    assert_eq!(position(&elements[&overlapping]), Some((550.0, 300.0)));
+    let Element::Line { x1, y1, x2, y2, .. } = elements[&line] else { unreachable!() };
+    assert_eq!((x1, y1, x2, y2), (0.0, 0.0, 165.0, 115.0));
+
+    // and in the file, only what isn't relative to the frame changes
+    let written = write_elements(&elements);
+    assert!(written.contains(&padded_header("!!!!Frame!x:150.0!y:90.0!width:500.0!height:400.0!z:-1!title:Outer!id:f!")));
+    assert!(written.contains(&padded_header("!!!!Frame!x:10.0!y:20.0!width:200.0!height:200.0!z:-1!id:g!parent:f!")));
+    assert!(written.contains(&padded_header("!!!!Rect!x:350.0!y:290.0!width:50.0!height:50.0!z:-1!color:#00827c!")));
+
+    // a parent that doesn't exist leaves the element where it is, with a diagnostic
+    let document = parse(&padded_header("!!!!Text!x:5.0!y:5.0!parent:nowhere!"));
+    assert_eq!(document.diagnostics.len(), 1);
+    assert_eq!(position(document.elements.values().next().unwrap()), Some((5.0, 5.0)));
+}
+
+#[test]
+fn check_update_test() {
+    let frame = |title: &str| Element::Frame {
+        x: 0.0, y: 0.0, width: 100.0, height: 100.0, z: -1, title: title.to_string(), id: None, parent: None, locked: false, hidden: false,
+    };
+    assert!(check_update(&frame("Notes"), &BTreeMap::new()).is_ok());
+
+    // anything that wouldn't be read back as it was sent is refused
+    for title in ["a!x:50", "a!b", "a\n!!!!Rect!x:0!y:0!!!!"] {
+        assert!(check_update(&frame(title), &BTreeMap::new()).is_err(), "{:?} was allowed", title);
+    }
+    let text_box = |raw_content: &str| Element::text_box(0.0, 0.0, raw_content.to_string());
+    assert!(check_update(&text_box("hello\n"), &BTreeMap::new()).is_ok());
+    assert!(check_update(&text_box("hello\n!!!!Image!src:/etc/passwd!!!!\n"), &BTreeMap::new()).is_err());
+
+    // and the rest is taken from parsing it, not from the page
+    let Element::TextBox { x, y, width, z, rotation, id, parent, locked, hidden, mut style, markdown, data, raw_content } = text_box("hello\n") else { unreachable!() };
+    style.color = Some(Color { written: "accent".to_string(), css: "red;background:url(x)".to_string() });
+    style.font = Some("serif}body{display:none".to_string());
+    let sent = Element::TextBox { x, y, width, z, rotation, id, parent, locked, hidden, style, markdown, data, raw_content };
+    let palette = BTreeMap::from([("accent".to_string(), Color::parse("#ff6b6b").unwrap())]);
+
+    let (checked, diagnostics) = check_update(&sent, &palette).unwrap();
+    assert_eq!(checked.write_repr(), sent.write_repr());
+    assert_eq!(diagnostics, ["invalid font value: serif}body{display:none"]);
+    let Element::TextBox { style, .. } = checked else { unreachable!() };
+    assert_eq!((style.color.map(|color| color.css), style.font), (Some("#ff6b6b".to_string()), None));
+}
+
+#[test]
+fn locked_hidden_test() {
+    let text = [
+        padded_header("!!!!Frame!x:0.0!y:0.0!width:500.0!height:400.0!z:-1!id:f!"),
+        padded_header("!!!!Rect!x:10.0!y:10.0!width:50.0!height:50.0!z:-1!color:#00827c!id:r!locked:true!"),
+        padded_header("!!!!Text!x:100.0!y:100.0!width:100.0!hidden:true!"),
+        padded_header("!!!!Line!x1:20.0!y1:20.0!x2:900.0!y2:20.0!from:r!locked:true!"),
+    ].concat();
+
+    let mut elements = parse(&text).elements;
+    assert_eq!(write_elements(&elements), text);
+    assert_eq!(elements.values().map(|element| (element.locked(), element.hidden())).collect::<Vec<_>>(),
+               [(false, false), (true, false), (false, true), (true, false)]);
+
+    // moving the frame leaves the locked rect behind, and the locked line attached to it
+    let key = elements.keys().next().unwrap().clone();
+    let mut moved = elements[&key].clone();
+    moved.translate(100.0, 0.0);
+    assert!(!moved.same_geometry(&elements[&key]));
+    assert!(move_dependents(&mut elements, &key, &moved));
+    elements.insert(key, moved);
+
+    let written = write_elements(&elements);
+    assert!(written.contains(&padded_header("!!!!Rect!x:10.0!y:10.0!width:50.0!height:50.0!z:-1!color:#00827c!id:r!locked:true!")));
+    assert!(written.contains(&padded_header("!!!!Text!x:200.0!y:100.0!width:100.0!hidden:true!")));
+    assert!(written.contains(&padded_header("!!!!Line!x1:20.0!y1:20.0!x2:900.0!y2:20.0!from:r!locked:true!")));
+
+    // anything but true or false is a diagnostic, and neither
+    let document = parse(&padded_header("!!!!Text!x:0.0!y:0.0!locked:yes!hidden:TRUE!"));
+    assert_eq!(document.elements.values().map(|element| (element.locked(), element.hidden())).collect::<Vec<_>>(), [(false, true)]);
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["invalid locked value: yes"]);
+}
+
+#[test]
+fn text_style_test() {
+    let text = padded_header("!!!!Text!x:0.0!y:0.0!background:#ffc857!border:#e0a526!padding:12!font:Georgia, serif!font_size:18!align:center!class:note urgent!") + "A sticky note\n";
+    let text_box = parse(&text).elements.into_values().next().unwrap();
+    assert_eq!(text_box.write_repr(), text);
+
+    let Element::TextBox { style, .. } = text_box else { panic!("not a text box") };
+    assert_eq!(style, TextStyle {
+        background: Color::parse("#ffc857"),
+        border: Color::parse("#e0a526"),
+        border_width: None,
+        padding: Some(12.0),
+        font: Some("Georgia, serif".to_string()),
+        font_size: Some(18.0),
+        color: None,
+        align: Some(TextAlign::Center),
+        class: Some("note urgent".to_string()),
+        rejected: BTreeMap::new(),
+    });
+
+    // anything that could get out of its css property is refused, with a diagnostic, but saved back as it was
+    let text = padded_header("!!!!Text!x:0.0!y:0.0!padding:-4!font:serif}body{display:none!font_size:0!align:middle!class:note\"onclick=!");
+    let document = parse(&text);
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), [
+        "invalid padding value: -4", "invalid font value: serif}body{display:none", "invalid font_size value: 0",
+        "invalid align value: middle", "invalid class value: note\"onclick=",
+    ]);
+    let text_box = document.elements.into_values().next().unwrap();
+    assert_eq!(text_box.write_repr(), text);
+    let Element::TextBox { style, .. } = text_box else { panic!("not a text box") };
+    assert_eq!((style.padding, style.font, style.font_size, style.align, style.class), (None, None, None, None, None));
+}
+
+#[test]
+fn palette_test() {
+    let rect = padded_header("!!!!Rect!x:0.0!y:0.0!width:100.0!height:50.0!z:-1!color:Accent!stroke:tomato!");
+    let line = padded_header("!!!!Line!x1:0.0!y1:0.0!x2:10.0!y2:10.0!color:rgb(0 0 0 / 50%)!");
+    let text_box = padded_header("!!!!Text!x:0.0!y:0.0!background:red;position:fixed!color:nope!");
+    let text = [padded_header("!!!!Palette!accent:#FF6B6B!ink:hsl(230, 32%, 9%)!"), "Some notes\n".to_string(), rect, line, text_box].concat();
+
+    let document = parse(&text);
+    assert_eq!(document.palette.values().map(|color| color.css.as_str()).collect::<Vec<_>>(), ["#ff6b6b", "#10121e"]);
+
+    // what's under the palette is a text box, which gets a header of its own when it's written
+    assert_eq!(write(&document), text.replacen("Some notes\n", &(padded_header("!!!!Text!") + "Some notes\n"), 1));
+
+    // and a palette further down the file stays there
+    let moved = [padded_header("!!!!Text!x:0.0!y:0.0!"), "Intro\n".to_string(), padded_header("!!!!Palette!accent:#FF6B6B!"), padded_header("!!!!Text!x:0.0!y:50.0!")].concat();
+    assert_eq!(write(&parse(&moved)), moved);
+    let at_end = [padded_header("!!!!Text!x:0.0!y:0.0!"), "Intro\n".to_string(), padded_header("!!!!Palette!accent:#FF6B6B!")].concat();
+    assert_eq!(write(&parse(&at_end)), at_end);
+
+    let colors = document.elements.values().flat_map(|element| match element {
+        Element::Rect { color, stroke, .. } => vec![Some(color.clone()), stroke.clone()],
+        Element::Line { color, .. } => vec![Some(color.clone())],
+        Element::TextBox { style, .. } => vec![style.background.clone(), style.color.clone()],
+        _ => vec![],
+    }).flatten().map(|color| color.css).collect::<Vec<_>>();
+    assert_eq!(colors, ["#ff6b6b", "#ff6347", "#00000080", color::INVALID, color::INVALID]);
+
+    // neither of the last two make it anywhere near the page, and both say why
+    assert_eq!(document.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(),
+               ["invalid background color: red;position:fixed", "there's no color called nope, in the palette or otherwise"]);
 }
@@ -0,0 +1,90 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::{cache, image};
+
+// Downscaled copies of local images, so a note full of 12 megapixel photos doesn't have the
+// browser decoding and scaling every one of them on each redraw. Thumbnails are made on the
+// first request and kept in the render cache, keyed by the source file's path, size and mtime -
+// so editing the image means a new thumbnail, and the stale one eventually gets evicted.
+//
+// There's no image decoding in here. The resizing is handed off to whichever of these tools is
+// installed, and if none of them are (or they all fail), there's no thumbnail and the caller
+// should fall back to the original.
+
+// image formats worth thumbnailing (gifs would lose their animation)
+pub const EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "webp"];
+
+// past this, it's as good as the original
+const MAX_WIDTH: u32 = 10000;
+
+// The width of the thumbnail for an image shown at shown pixels wide: twice that for high dpi
+// screens, rounded up to the next hundred so a few pixels' difference in box width doesn't mean a
+// whole new thumbnail. These are the only widths that are made, so a page can't fill the cache
+// with every size from 1 to 4 billion.
+pub fn width(shown: u32) -> u32 {
+    (shown.min(MAX_WIDTH / 2).max(1) * 2 + 99) / 100 * 100
+}
+
+pub fn thumbnail(source: &Path, width: u32) -> Option<Vec<u8>> {
+    if width == 0 || width % 100 != 0 || width > MAX_WIDTH { return None; }
+    if !EXTENSIONS.contains(&extension(source)?.as_str()) { return None; }
+
+    let modified = fs::metadata(source).ok()?.modified().ok()?;
+    let modified = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_nanos();
+
+    cache::get_or_render_bytes("thumbnail", &width.to_string(), &format!("{}@{}", source.display(), modified), || resize(source, width))
+}
+
+// "image/jpeg" and so on, for serving a thumbnail of source
+pub fn content_type(source: &Path) -> &'static str {
+    match extension(source).as_deref() {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    Some(path.extension()?.to_str()?.to_lowercase())
+}
+
+// Scale source down to width pixels wide (never up), in the same format.
+fn resize(source: &Path, width: u32) -> Option<Vec<u8>> {
+    // Only ever hand the tools a real image file, and by its absolute path: imagemagick reads a
+    // name like "-write" as an option, "msl:notes.png" as a script, and an svg called "a.png" as
+    // an svg.
+    let source = fs::canonicalize(source).ok()?;
+    if !fs::metadata(&source).ok()?.is_file() || image::dimensions(&source).is_none() { return None; }
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let out = std::env::temp_dir().join(format!(
+        "dungeon-thumbnail-{}-{}.{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed), extension(&source)?,
+    ));
+
+    let (source, out_arg) = (source.as_os_str().to_owned(), out.as_os_str().to_owned());
+    let size = format!("{}x>", width); // imagemagick geometry, ">" for only ever shrinking
+
+    let commands: [(&str, Vec<OsString>); 4] = [
+        ("magick", vec![source.clone(), "-auto-orient".into(), "-thumbnail".into(), size.clone().into(), out_arg.clone()]),
+        ("convert", vec![source.clone(), "-auto-orient".into(), "-thumbnail".into(), size.into(), out_arg.clone()]), // imagemagick 6
+        ("sips", vec!["--resampleWidth".into(), width.to_string().into(), source.clone(), "--out".into(), out_arg.clone()]), // macos
+        ("vipsthumbnail", vec![source, "--size".into(), format!("{}x", width).into(), "-o".into(), out_arg]),
+    ];
+
+    // (on windows, convert is the tool that turns a FAT drive into NTFS)
+    let resized = commands.iter().filter(|(program, _)| !(cfg!(windows) && *program == "convert")).any(|(program, args)| {
+        Command::new(program).args(args)
+            .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    });
+
+    let bytes = if resized { fs::read(&out).ok() } else { None };
+    let _ = fs::remove_file(&out);
+    bytes.filter(|bytes| !bytes.is_empty())
+}